@@ -0,0 +1,467 @@
+use chrono::{DateTime, Local};
+use hyper::header::{HeaderValue, ALLOW, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::watch;
+
+use crate::report::{IndexerReport, Outcome, RunReport, SourceIndexerReport};
+use crate::schedule::SyncScope;
+use crate::{Error, Result};
+
+/// What polling mode has been up to, as reported by the control API
+#[derive(Clone, Default)]
+pub struct Status(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    syncing: Option<RunningSync>,
+    last_run: Option<LastRun>,
+    destinations: BTreeMap<String, DestinationHealth>,
+    indexers: Vec<SourceIndexerReport>,
+    /// What each destination did with each indexer when it was last synced, by indexer ID
+    mapping: BTreeMap<String, BTreeMap<String, IndexerReport>>,
+}
+
+#[derive(Serialize)]
+struct RunningSync {
+    started: DateTime<Local>,
+    scope: SyncScope,
+}
+
+#[derive(Serialize)]
+struct LastRun {
+    started: DateTime<Local>,
+    finished: DateTime<Local>,
+    scope: SyncScope,
+    report: RunReport,
+}
+
+#[derive(Default, Serialize)]
+struct DestinationHealth {
+    last_success: Option<DateTime<Local>>,
+    last_failure: Option<DateTime<Local>>,
+    consecutive_failures: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+impl Status {
+    pub fn new<'a>(destinations: impl IntoIterator<Item = &'a String>) -> Self {
+        let state = State {
+            destinations: destinations
+                .into_iter()
+                .map(|name| (name.clone(), DestinationHealth::default()))
+                .collect(),
+            ..State::default()
+        };
+        Self(Arc::new(Mutex::new(state)))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Nothing panics while holding the lock, but carry on with whatever was recorded if it did
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn started(&self, scope: &SyncScope) {
+        self.lock().syncing = Some(RunningSync {
+            started: Local::now(),
+            scope: scope.clone(),
+        });
+    }
+
+    pub fn finished(&self, run: RunReport) {
+        let finished = Local::now();
+        let mut state = self.lock();
+        let RunningSync { started, scope } = match state.syncing.take() {
+            Some(sync) => sync,
+            None => return,
+        };
+
+        // A sync which couldn't fetch indexers leaves the last known ones
+        if !run.indexers.is_empty() {
+            state.indexers = run.indexers.clone();
+        }
+
+        for report in &run.destinations {
+            let mapping = state.mapping.entry(report.destination.clone()).or_default();
+            for indexer in &report.indexers {
+                match indexer.outcome {
+                    Outcome::Pruned => mapping.remove(&indexer.id),
+                    _ => mapping.insert(indexer.id.clone(), indexer.clone()),
+                };
+            }
+        }
+
+        for (name, health) in &mut state.destinations {
+            let error = match run.destinations.iter().find(|d| &d.destination == name) {
+                Some(report) if report.failed_entirely() => Some(format!(
+                    "no indexers were synced ({} failed)",
+                    report.failures()
+                )),
+                Some(_) => None,
+                None => match run.destination_errors.get(name) {
+                    Some(error) => Some(error.clone()),
+                    // It wasn't part of this sync, or the sync never got as far as updating it
+                    None => continue,
+                },
+            };
+
+            match error {
+                Some(error) => {
+                    health.last_failure = Some(finished);
+                    health.consecutive_failures += 1;
+                    health.last_error = Some(error);
+                }
+                None => {
+                    health.last_success = Some(finished);
+                    health.consecutive_failures = 0;
+                    health.last_error = None;
+                }
+            }
+        }
+
+        state.last_run = Some(LastRun {
+            started,
+            finished,
+            scope,
+            report: run,
+        });
+    }
+}
+
+/// A source indexer, and what each destination last did with it
+#[derive(Serialize)]
+struct MappedIndexer<'a> {
+    #[serde(flatten)]
+    indexer: &'a SourceIndexerReport,
+    destinations: BTreeMap<&'a str, &'a IndexerReport>,
+}
+
+/// The control API for polling mode:
+///
+/// - `POST /sync` asks for a sync as soon as possible, of everything or only of the indexers
+///   matching `?indexer=PATTERN` or the destination given by `?destination=NAME`. Only a few
+///   requests can wait for their turn, after which it answers 429 Too Many Requests.
+/// - `GET /status` describes the running sync, the last one, when the next is due and the health
+///   of each destination
+/// - `GET /indexers` lists the indexers in every source and what each destination did with them
+/// - `GET /metrics` gives metrics in Prometheus' text format
+pub struct Api {
+    status: Status,
+    requester: mpsc::Sender<SyncScope>,
+    next_sync: watch::Receiver<Option<DateTime<Local>>>,
+}
+
+impl Api {
+    pub fn new(
+        status: Status,
+        requester: mpsc::Sender<SyncScope>,
+        next_sync: watch::Receiver<Option<DateTime<Local>>>,
+    ) -> Self {
+        Self {
+            status,
+            requester,
+            next_sync,
+        }
+    }
+
+    fn handle(&self, request: &Request<Body>) -> Response<Body> {
+        let (allowed, allow) = match request.uri().path() {
+            "/sync" => (Method::POST, "POST"),
+            "/status" | "/indexers" | "/metrics" => (Method::GET, "GET"),
+            _ => return error(StatusCode::NOT_FOUND, "Not found".to_owned()),
+        };
+        if request.method() != allowed {
+            let mut response = error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!("Only {} is allowed", allow),
+            );
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static(allow));
+            return response;
+        }
+
+        match request.uri().path() {
+            "/sync" => self.sync(request.uri().query().unwrap_or_default()),
+            "/status" => self.status(),
+            "/metrics" => metrics(),
+            _ => self.indexers(),
+        }
+    }
+
+    fn sync(&self, query: &str) -> Response<Body> {
+        let mut scope = SyncScope::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "indexer" => match value.parse() {
+                    Ok(pattern) => scope.indexer = Some(pattern),
+                    Err(e) => return error(StatusCode::BAD_REQUEST, e),
+                },
+                "destination" => {
+                    if !self.status.lock().destinations.contains_key(value.as_ref()) {
+                        let message = format!("Unknown destination {:?}", value);
+                        return error(StatusCode::NOT_FOUND, message);
+                    }
+                    scope.destination = Some(value.into_owned());
+                }
+                other => {
+                    let message = format!("Unknown parameter {:?}", other);
+                    return error(StatusCode::BAD_REQUEST, message);
+                }
+            }
+        }
+
+        match self.requester.try_send(scope.clone()) {
+            Ok(()) => json(StatusCode::ACCEPTED, &serde_json::json!({ "scope": scope })),
+            Err(TrySendError::Full(_)) => error(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many syncs are already waiting".to_owned(),
+            ),
+            Err(TrySendError::Closed(_)) => {
+                error(StatusCode::SERVICE_UNAVAILABLE, "Shutting down".to_owned())
+            }
+        }
+    }
+
+    fn status(&self) -> Response<Body> {
+        let state = self.status.lock();
+        let next_sync = *self.next_sync.borrow();
+        json(
+            StatusCode::OK,
+            &serde_json::json!({
+                "syncing": state.syncing,
+                "next_sync": next_sync,
+                "last_run": state.last_run,
+                "destinations": state.destinations,
+            }),
+        )
+    }
+
+    fn indexers(&self) -> Response<Body> {
+        let state = self.status.lock();
+        let indexers: Vec<_> = state
+            .indexers
+            .iter()
+            .map(|indexer| MappedIndexer {
+                indexer,
+                destinations: state
+                    .mapping
+                    .iter()
+                    .filter_map(|(name, mapping)| Some((name.as_str(), mapping.get(&indexer.id)?)))
+                    .collect(),
+            })
+            .collect();
+        json(StatusCode::OK, &indexers)
+    }
+}
+
+fn metrics() -> Response<Body> {
+    let (content_type, body) = crate::metrics::render();
+    let mut response = Response::new(Body::from(body));
+    if let Ok(content_type) = HeaderValue::from_str(&content_type) {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    response
+}
+
+fn json(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    let mut response = match serde_json::to_vec(body) {
+        Ok(body) => Response::new(Body::from(body)),
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn error(status: StatusCode, message: String) -> Response<Body> {
+    json(status, &serde_json::json!({ "error": message }))
+}
+
+/// Serve the control API on the given address until we exit, returning the address it's
+/// listening on
+pub fn serve(addr: SocketAddr, api: Api) -> Result<SocketAddr> {
+    let api = Arc::new(api);
+    let make_service = make_service_fn(move |_| {
+        let api = api.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let response = api.handle(&request);
+                log::debug!(
+                    "{} {} -> {}",
+                    request.method(),
+                    request.uri(),
+                    response.status()
+                );
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)
+        .map_err(|e| Error::Config(format!("unable to listen on {}: {}", addr, e)))?
+        .serve(make_service);
+    let addr = server.local_addr();
+    log::info!("Control API listening on http://{}", addr);
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("Control API stopped: {}", e);
+        }
+    });
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::DestinationReport;
+
+    fn api() -> (Api, mpsc::Receiver<SyncScope>) {
+        let destinations = ["sonarr".to_owned(), "sonarr-4k".to_owned()];
+        let (requester, requests) = mpsc::channel(2);
+        let (_, next_sync) = watch::channel(None);
+        let api = Api::new(Status::new(&destinations), requester, next_sync);
+        (api, requests)
+    }
+
+    fn request(method: Method, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> serde_json::Value {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sync_requests() {
+        let (api, mut requests) = api();
+
+        let response = api.handle(&request(Method::POST, "/sync"));
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let scope = requests.recv().await.unwrap();
+        assert!(scope.indexer.is_none() && scope.destination.is_none());
+
+        let uri = "/sync?indexer=id%3Ajackett%3Ararbg&destination=sonarr-4k";
+        let response = api.handle(&request(Method::POST, uri));
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            body(response).await,
+            serde_json::json!({
+                "scope": { "indexer": "id:jackett:rarbg", "destination": "sonarr-4k" }
+            })
+        );
+        let scope = requests.recv().await.unwrap();
+        assert_eq!(scope.destination.as_deref(), Some("sonarr-4k"));
+
+        let response = api.handle(&request(Method::POST, "/sync?destination=radarr"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = api.handle(&request(Method::POST, "/sync?indexer=/(/"));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = api.handle(&request(Method::POST, "/sync?force=1"));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(requests.try_recv().is_err());
+
+        for _ in 0..2 {
+            let response = api.handle(&request(Method::POST, "/sync"));
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+        }
+        let response = api.handle(&request(Method::POST, "/sync"));
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        requests.recv().await.unwrap();
+        let response = api.handle(&request(Method::POST, "/sync"));
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let response = api.handle(&request(Method::GET, "/sync"));
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "POST");
+        let response = api.handle(&request(Method::GET, "/"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = api.handle(&request(Method::GET, "/metrics"));
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_status_and_indexers() {
+        let (api, _requests) = api();
+
+        let status = body(api.handle(&request(Method::GET, "/status"))).await;
+        assert_eq!(status["last_run"], serde_json::Value::Null);
+        assert_eq!(status["destinations"]["sonarr"]["consecutive_failures"], 0);
+
+        let mut rarbg = IndexerReport::new("jackett:rarbg", "RARBG (Jackett) {jackett:rarbg}");
+        rarbg.outcome = Outcome::Created;
+        let mut old = IndexerReport::new("jackett:eztv", "EZTV (Jackett) {jackett:eztv}");
+        old.outcome = Outcome::Pruned;
+        let mut sonarr = DestinationReport::new("sonarr");
+        sonarr.record(rarbg);
+        sonarr.record(old);
+
+        api.status.started(&SyncScope::default());
+        let status = body(api.handle(&request(Method::GET, "/status"))).await;
+        assert!(status["syncing"]["started"].is_string());
+
+        api.status.finished(RunReport {
+            indexers: vec![SourceIndexerReport {
+                id: "jackett:rarbg".to_owned(),
+                name: "RARBG".to_owned(),
+                privacy: crate::IndexerPrivacy::Public,
+                protocols: vec![],
+                selected: true,
+                matched: None,
+            }],
+            destinations: vec![sonarr],
+            errors: vec!["Unable to update sonarr-4k: HTTP error: timed out".to_owned()],
+            destination_errors: vec![("sonarr-4k".to_owned(), "HTTP error: timed out".to_owned())]
+                .into_iter()
+                .collect(),
+        });
+
+        let status = body(api.handle(&request(Method::GET, "/status"))).await;
+        assert_eq!(status["syncing"], serde_json::Value::Null);
+        assert!(status["last_run"]["finished"].is_string());
+        assert!(status["destinations"]["sonarr"]["last_success"].is_string());
+        assert_eq!(
+            status["destinations"]["sonarr-4k"],
+            serde_json::json!({
+                "last_success": null,
+                "last_failure": status["destinations"]["sonarr-4k"]["last_failure"],
+                "consecutive_failures": 1,
+                "last_error": "HTTP error: timed out",
+            })
+        );
+
+        let indexers = body(api.handle(&request(Method::GET, "/indexers"))).await;
+        assert_eq!(
+            indexers,
+            serde_json::json!([{
+                "id": "jackett:rarbg",
+                "name": "RARBG",
+                "privacy": "public",
+                "protocols": [],
+                "selected": true,
+                "destinations": {
+                    "sonarr": {
+                        "id": "jackett:rarbg",
+                        "name": "RARBG (Jackett) {jackett:rarbg}",
+                        "outcome": "created",
+                    },
+                },
+            }])
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use serde::{de, Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use url::Url;
+
+use crate::destination::sonarr::TestFailurePolicy;
+use crate::filter::IndexerFilter;
+use crate::http::{self, BasicAuth, Headers, Proxy, RetryPolicy, TlsConfig};
+use crate::report::{FailurePolicy, OutputFormat};
+use crate::socket::UnixSocket;
+use crate::util::UrlRewrite;
+use crate::{Error, FeedProtocol, Result, SeedCriteria};
+
+/// Everything needed to sync, as read from a config file (TOML or YAML) and then overridden by
+/// command line options and environment variables
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(deserialize_with = "optional_duration")]
+    pub interval: Option<Duration>,
+    #[serde(deserialize_with = "optional_cron")]
+    pub schedule: Option<cron::Schedule>,
+    pub max_consecutive_failures: Option<usize>,
+    /// Where to serve the control API in polling mode
+    pub listen: Option<SocketAddr>,
+    pub fail_on: FailurePolicy,
+    pub output: OutputFormat,
+    pub retry: RetryPolicy,
+    /// Which indexers to sync to every destination
+    pub filter: IndexerFilter,
+    /// Seed criteria for destinations which don't give their own
+    pub seed: SeedConfig,
+    pub sources: BTreeMap<String, SourceConfig>,
+    pub destinations: BTreeMap<String, DestinationConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceConfig {
+    Jackett(JackettConfig),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct JackettConfig {
+    pub url: Url,
+    /// The admin password, if it isn't given in the URL
+    #[serde(default, deserialize_with = "crate::secret::optional")]
+    pub password: Option<String>,
+    /// The URL destinations should use to reach Jackett's feeds, if it isn't the same as `url`
+    #[serde(default)]
+    pub feed_url: Option<Url>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// The proxy to reach Jackett through, or "none", rather than the one in the environment
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+    #[serde(default)]
+    pub headers: Headers,
+    /// Credentials for a reverse proxy in front of Jackett
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuth>,
+    /// The socket Jackett is reached through, when `url` was given as a `unix://` URL
+    #[serde(skip)]
+    pub socket: Option<UnixSocket>,
+}
+
+impl JackettConfig {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            password: None,
+            feed_url: None,
+            tls: TlsConfig::default(),
+            proxy: None,
+            headers: Headers::default(),
+            basic_auth: None,
+            socket: None,
+        }
+    }
+
+    /// A client which connects to Jackett as configured
+    pub fn client(&self) -> Result<http::ClientBuilder> {
+        http::client(
+            self.socket.as_ref(),
+            &self.tls,
+            self.proxy.as_ref(),
+            &self.headers,
+            self.basic_auth.as_ref(),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DestinationConfig {
+    Sonarr(SonarrConfig),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SonarrConfig {
+    pub url: Url,
+    /// The API key, if it isn't given in the URL
+    #[serde(default, deserialize_with = "crate::secret::optional")]
+    pub api_key: Option<String>,
+    /// Which of the indexers selected by the top-level filter to sync to this destination
+    #[serde(default)]
+    pub filter: IndexerFilter,
+    #[serde(default)]
+    pub seed: SeedConfig,
+    #[serde(default = "default_protocols")]
+    pub protocols: Vec<FeedProtocol>,
+    #[serde(default = "default_test_failure")]
+    pub test_failure: TestFailurePolicy,
+    #[serde(default)]
+    pub force_save: bool,
+    #[serde(default)]
+    pub prune: bool,
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Only use these of each indexer's TV categories
+    #[serde(default)]
+    pub categories: Option<Vec<usize>>,
+    /// Only use these of each indexer's anime categories
+    #[serde(default)]
+    pub anime_categories: Option<Vec<usize>>,
+    /// Rewrite feed URLs, for when this destination reaches sources at other addresses
+    #[serde(default)]
+    pub rewrite: Vec<UrlRewrite>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// The proxy to reach Sonarr through, or "none", rather than the one in the environment
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+    #[serde(default)]
+    pub headers: Headers,
+    /// Credentials for a reverse proxy in front of Sonarr, as the API key is sent separately
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuth>,
+    /// The socket Sonarr is reached through, when `url` was given as a `unix://` URL
+    #[serde(skip)]
+    pub socket: Option<UnixSocket>,
+}
+
+impl SonarrConfig {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            api_key: None,
+            filter: IndexerFilter::default(),
+            seed: SeedConfig::default(),
+            protocols: default_protocols(),
+            test_failure: default_test_failure(),
+            force_save: false,
+            prune: false,
+            concurrency: default_concurrency(),
+            categories: None,
+            anime_categories: None,
+            rewrite: vec![],
+            tls: TlsConfig::default(),
+            proxy: None,
+            headers: Headers::default(),
+            basic_auth: None,
+            socket: None,
+        }
+    }
+
+    /// A client which connects to Sonarr as configured
+    pub fn client(&self) -> Result<http::ClientBuilder> {
+        http::client(
+            self.socket.as_ref(),
+            &self.tls,
+            self.proxy.as_ref(),
+            &self.headers,
+            self.basic_auth.as_ref(),
+        )
+    }
+}
+
+fn default_protocols() -> Vec<FeedProtocol> {
+    FeedProtocol::DEFAULT_PREFERENCE.to_vec()
+}
+
+fn default_test_failure() -> TestFailurePolicy {
+    TestFailurePolicy::Skip
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+/// Seed criteria, which may be given separately for public and private trackers
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SeedConfig {
+    pub ratio: Option<f32>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub time: Option<Duration>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub season_pack_time: Option<Duration>,
+    pub public: SeedOverrides,
+    pub private: SeedOverrides,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SeedOverrides {
+    pub ratio: Option<f32>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub time: Option<Duration>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub season_pack_time: Option<Duration>,
+}
+
+impl SeedConfig {
+    /// The public and private seed criteria. Anything not given for public or private trackers
+    /// falls back to the general settings. A season pack's seed time falls back to the tracker's
+    /// own seed time before the general season pack seed time, then the general seed time.
+    pub fn criteria(&self) -> (SeedCriteria, SeedCriteria) {
+        let resolve = |overrides: &SeedOverrides| SeedCriteria {
+            seed_ratio: overrides.ratio.or(self.ratio),
+            seed_time: overrides.time.or(self.time),
+            season_pack_seed_time: overrides
+                .season_pack_time
+                .or(overrides.time)
+                .or(self.season_pack_time)
+                .or(self.time),
+        };
+        (resolve(&self.public), resolve(&self.private))
+    }
+
+    /// The public and private seed criteria for a destination with these settings, using
+    /// `fallback` for anything the destination doesn't specify
+    pub fn criteria_or(&self, fallback: &SeedConfig) -> (SeedCriteria, SeedCriteria) {
+        let (public, private) = self.criteria();
+        let (fallback_public, fallback_private) = fallback.criteria();
+        (public.or(fallback_public), private.or(fallback_private))
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let invalid =
+            |e: &dyn std::fmt::Display| Error::Config(format!("{}: {}", path.display(), e));
+        let contents = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+
+        let config: Self = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| invalid(&e))?,
+            Some("yaml") | Some("yml") => {
+                serde_yaml::from_str(&contents).map_err(|e| invalid(&e))?
+            }
+            _ => return Err(invalid(&"expected a .toml, .yaml or .yml file")),
+        };
+
+        if config.interval.is_some() && config.schedule.is_some() {
+            return Err(invalid(&"only one of interval and schedule may be given"));
+        }
+
+        for url in config
+            .sources
+            .values()
+            .map(SourceConfig::url)
+            .chain(config.destinations.values().map(DestinationConfig::url))
+        {
+            crate::util::is_http_url(url.as_str()).map_err(|e| invalid(&e))?;
+        }
+
+        Ok(config)
+    }
+}
+
+impl SourceConfig {
+    pub fn url(&self) -> &Url {
+        match self {
+            Self::Jackett(jackett) => &jackett.url,
+        }
+    }
+}
+
+impl DestinationConfig {
+    pub fn url(&self) -> &Url {
+        match self {
+            Self::Sonarr(sonarr) => &sonarr.url,
+        }
+    }
+}
+
+/// Durations are written as they are on the command line ("1h", "2 weeks", etc)
+pub fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_duration::parse::parse(&s).map_err(de::Error::custom)
+}
+
+fn optional_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    duration(deserializer).map(Some)
+}
+
+fn optional_cron<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<cron::Schedule>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    crate::schedule::parse_cron(&s)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_toml() -> std::result::Result<(), toml::de::Error> {
+        let config: Config = toml::from_str(
+            r#"
+            interval = "1h"
+            listen = "127.0.0.1:8080"
+            filter = { include = ["rarbg"] }
+
+            [seed]
+            ratio = 1.5
+            [seed.private]
+            time = "1 week"
+
+            [sources.jackett]
+            type = "jackett"
+            url = "http://localhost:9117"
+            password = "hunter2"
+            proxy = "socks5h://localhost:1080"
+
+            [destinations.anime]
+            type = "sonarr"
+            url = "http://sonarr-anime:8989"
+            protocols = ["torznab"]
+            anime-categories = [5070]
+            rewrite = [{ from = "http://localhost:9117", to = "http://jackett:9117" }]
+
+            [destinations.anime.seed]
+            ratio = 10.0
+            "#,
+        )?;
+
+        assert_eq!(config.interval, Some(Duration::from_secs(3600)));
+        assert_eq!(config.listen, Some(([127, 0, 0, 1], 8080).into()));
+        assert_eq!(config.retry.retries, RetryPolicy::default().retries);
+
+        let SourceConfig::Jackett(jackett) = &config.sources["jackett"];
+        assert_eq!(jackett.password.as_deref(), Some("hunter2"));
+        assert!(matches!(jackett.proxy, Some(Proxy::Url(_))));
+
+        let DestinationConfig::Sonarr(sonarr) = &config.destinations["anime"];
+        assert_eq!(sonarr.protocols, vec![FeedProtocol::Torznab]);
+        assert_eq!(sonarr.concurrency, 4);
+        assert_eq!(sonarr.anime_categories, Some(vec![5070]));
+        assert_eq!(sonarr.rewrite[0].to.as_str(), "http://jackett:9117/");
+
+        let (public, private) = sonarr.seed.criteria_or(&config.seed);
+        assert_eq!(public.seed_ratio, Some(10.0));
+        assert_eq!(private.seed_ratio, Some(10.0));
+        assert_eq!(public.seed_time, None);
+        assert_eq!(private.seed_time, Some(Duration::from_secs(7 * 24 * 3600)));
+        assert_eq!(private.season_pack_seed_time, private.seed_time);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_yaml() -> serde_yaml::Result<()> {
+        let config: Config = serde_yaml::from_str(
+            r#"
+            schedule: "0 */6 * * *"
+            retry:
+              retries: 5
+              timeout: 30s
+            sources:
+              jackett:
+                type: jackett
+                url: http://localhost:9117
+            destinations:
+              sonarr:
+                type: sonarr
+                url: http://sonarr:8989
+                api-key: abc123
+                headers:
+                  X-Token: def456
+                basic-auth:
+                  username: indexer-sync
+                  password: hunter2
+            "#,
+        )?;
+
+        assert!(config.schedule.is_some());
+        assert_eq!(config.retry.retries, 5);
+        assert_eq!(config.retry.timeout, Duration::from_secs(30));
+
+        let DestinationConfig::Sonarr(sonarr) = &config.destinations["sonarr"];
+        assert_eq!(sonarr.api_key.as_deref(), Some("abc123"));
+        assert_eq!(
+            format!("{:?}", sonarr.headers),
+            r#"{"X-Token": "<redacted>"}"#
+        );
+        let basic_auth = sonarr.basic_auth.as_ref().unwrap();
+        assert_eq!(basic_auth.password.as_deref(), Some("hunter2"));
+        assert_eq!(sonarr.protocols, FeedProtocol::DEFAULT_PREFERENCE.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_season_pack_seed_time_precedence() {
+        let day = Duration::from_secs(24 * 3600);
+        let seed = SeedConfig {
+            time: Some(day),
+            season_pack_time: Some(2 * day),
+            private: SeedOverrides {
+                time: Some(3 * day),
+                ..SeedOverrides::default()
+            },
+            ..SeedConfig::default()
+        };
+        let (public, private) = seed.criteria();
+        assert_eq!(public.seed_time, Some(day));
+        assert_eq!(public.season_pack_seed_time, Some(2 * day));
+        assert_eq!(private.seed_time, Some(3 * day));
+        assert_eq!(private.season_pack_seed_time, Some(3 * day));
+
+        let seed = SeedConfig {
+            time: Some(day),
+            ..SeedConfig::default()
+        };
+        let (public, _) = seed.criteria();
+        assert_eq!(public.season_pack_seed_time, Some(day));
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        let result = toml::from_str::<Config>(
+            r#"
+            [destinations.sonarr]
+            type = "sonarr"
+            url = "http://sonarr:8989"
+            api_token = "abc123"
+            "#,
+        );
+        assert!(result.is_err());
+    }
+}
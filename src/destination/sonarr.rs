@@ -1,44 +1,145 @@
+use crate::http::{self, RetryPolicy, Upstream};
+use crate::report::{DestinationReport, IndexerReport, Outcome};
+use crate::util::{is_valid_name, rewrite_url, UrlRewrite};
 use crate::znab::{Capabilities, Ids};
-use crate::{FeedUrls, SeedCriteria};
+use crate::{Error, Feed, FeedProtocol, Result, SeedCriteria, ValidationFailure};
+use futures::stream::{self, StreamExt};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::str::FromStr;
 use std::time::Duration;
 use url::Url;
 
 pub struct Sonarr {
+    name: String,
     url: Url,
-    client: reqwest::Client,
+    client: http::Client,
     public_seed_criteria: SeedCriteria,
     private_seed_criteria: SeedCriteria,
+    protocol_preference: Vec<FeedProtocol>,
+    test_failure_policy: TestFailurePolicy,
+    force_save: bool,
+    prune: bool,
+    concurrency: usize,
+    retry: RetryPolicy,
+    categories: Option<Vec<usize>>,
+    anime_categories: Option<Vec<usize>>,
+    url_rewrites: Vec<UrlRewrite>,
+}
+
+/// What to do with an indexer which fails Sonarr's indexer test
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TestFailurePolicy {
+    /// Report the failure and leave Sonarr untouched
+    Skip,
+    /// Report the failure and save the indexer with searching and RSS disabled
+    Disable,
 }
 
-pub fn new(url: Url) -> Result<Sonarr, Box<dyn std::error::Error>> {
-    use reqwest::header::{self, HeaderMap, HeaderValue};
+impl FromStr for TestFailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "skip" => Ok(Self::Skip),
+            "disable" => Ok(Self::Disable),
+            other => Err(format!(
+                "Unknown test failure policy {:?} (expected skip or disable)",
+                other
+            )),
+        }
+    }
+}
 
-    let (url, api_key) = crate::util::extract_single_auth_value(url);
-    let api_key = api_key.ok_or("Sonarr API key is required")?;
+/// Connect to Sonarr with the given API key, or otherwise the one in the URL's userinfo
+pub fn new(url: Url, api_key: Option<String>, client: http::ClientBuilder) -> Result<Sonarr> {
+    use reqwest::header::{self, HeaderName, HeaderValue};
 
-    let mut headers = HeaderMap::new();
-    headers.insert("X-Api-Key", HeaderValue::from_str(&api_key)?);
-    headers.insert(header::ACCEPT, HeaderValue::from_str("application/json")?);
+    let (url, url_api_key) = crate::util::extract_single_auth_value(crate::util::base_url(url));
+    let api_key = api_key
+        .or(url_api_key)
+        .ok_or_else(|| Error::Auth("Sonarr API key is required".to_owned()))?;
 
-    let client = reqwest::Client::builder()
-        .default_headers(headers)
+    let client = client
+        .header(
+            HeaderName::from_static("x-api-key"),
+            HeaderValue::from_str(&api_key)?,
+        )
+        .header(header::ACCEPT, HeaderValue::from_static("application/json"))
         .build()?;
     Ok(Sonarr {
+        name: "Sonarr".to_owned(),
         client,
         url,
         public_seed_criteria: SeedCriteria::default(),
         private_seed_criteria: SeedCriteria::default(),
+        protocol_preference: FeedProtocol::DEFAULT_PREFERENCE.to_vec(),
+        test_failure_policy: TestFailurePolicy::Skip,
+        force_save: false,
+        prune: false,
+        concurrency: 1,
+        retry: RetryPolicy::default(),
+        categories: None,
+        anime_categories: None,
+        url_rewrites: vec![],
     })
 }
 
+/// Read the URL and API key of a Sonarr on this machine from its `config.xml`
+pub fn discover(config_xml: &Path) -> Result<(Url, String)> {
+    let invalid =
+        |e: &dyn std::fmt::Display| Error::Config(format!("{}: {}", config_xml.display(), e));
+    let xml = std::fs::read_to_string(config_xml).map_err(|e| invalid(&e))?;
+    parse_config_xml(&xml).map_err(|e| invalid(&e))
+}
+
+fn parse_config_xml(xml: &str) -> std::result::Result<(Url, String), String> {
+    let document = roxmltree::Document::parse(xml).map_err(|e| e.to_string())?;
+    let setting = |name: &str| {
+        document
+            .root_element()
+            .children()
+            .find(|node| node.has_tag_name(name))
+            .and_then(|node| node.text())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+
+    let api_key = setting("ApiKey").ok_or("no ApiKey found")?;
+    let ssl = matches!(setting("EnableSsl"), Some(ssl) if ssl.eq_ignore_ascii_case("true"));
+    let (scheme, port) = if ssl {
+        ("https", setting("SslPort").unwrap_or("9898"))
+    } else {
+        ("http", setting("Port").unwrap_or("8989"))
+    };
+    // Sonarr listens on every address unless it's bound to a particular one
+    let host = match setting("BindAddress") {
+        None | Some("*") | Some("0.0.0.0") | Some("::") => "localhost".to_owned(),
+        Some(address) if address.contains(':') => format!("[{}]", address),
+        Some(address) => address.to_owned(),
+    };
+    let base = setting("UrlBase").unwrap_or("").trim_matches('/');
+
+    let mut url = format!("{}://{}:{}/", scheme, host, port);
+    if !base.is_empty() {
+        url.push_str(base);
+        url.push('/');
+    }
+    let url = url
+        .parse()
+        .map_err(|e| format!("invalid URL {:?}: {}", url, e))?;
+    Ok((url, api_key.to_owned()))
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum Protocol {
     Torrent,
     Usenet,
 }
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum ConfiguredProtocol {
     Torrent {
@@ -69,7 +170,7 @@ enum ConfigContract {
     Other,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(into = "SonarrIndexerSchema", try_from = "SonarrIndexerSchema")]
 struct SonarrIndexer {
     id: Option<usize>,
@@ -81,6 +182,9 @@ struct SonarrIndexer {
     implementation: Implementation,
     config_contract: ConfigContract,
     protocol: ConfiguredProtocol,
+    enable_rss: bool,
+    enable_automatic_search: bool,
+    enable_interactive_search: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -101,8 +205,17 @@ struct SonarrIndexerSchema {
     fields: Vec<Field>,
 }
 
-impl std::convert::From<SonarrIndexerSchema> for SonarrIndexer {
-    fn from(from: SonarrIndexerSchema) -> Self {
+impl TryFrom<SonarrIndexerSchema> for SonarrIndexer {
+    type Error = Error;
+
+    fn try_from(from: SonarrIndexerSchema) -> Result<Self> {
+        let missing = |field: &str| {
+            Error::Schema(format!(
+                "{} field should always be present for torrent indexers (missing from {:?})",
+                field, from.name
+            ))
+        };
+
         let api_key = from
             .fields
             .iter()
@@ -157,7 +270,7 @@ impl std::convert::From<SonarrIndexerSchema> for SonarrIndexer {
                         Field::SeedRatio { value } => Some(value.to_owned()),
                         _ => None,
                     })
-                    .expect("seedCriteria.seedRatio field should always be present for torrent indexers"),
+                    .ok_or_else(|| missing("seedCriteria.seedRatio"))?,
                 minimum_seeders: from
                     .fields
                     .iter()
@@ -165,27 +278,31 @@ impl std::convert::From<SonarrIndexerSchema> for SonarrIndexer {
                         Field::MinimumSeeders { value } => Some(value.to_owned()),
                         _ => None,
                     })
-                    .expect("minimumSeeders field should always be present for torrent indexers"),
+                    .ok_or_else(|| missing("minimumSeeders"))?,
                 seed_time: from
                     .fields
                     .iter()
                     .find_map(|f| match f {
-                        Field::SeedTime { value } => Some(value.map(|secs| Duration::from_secs(secs * 60))),
+                        Field::SeedTime { value } => {
+                            Some(value.map(|secs| Duration::from_secs(secs * 60)))
+                        }
                         _ => None,
                     })
-                    .expect("seedCriteria.seedTime field should always be present for torrent indexers"),
+                    .ok_or_else(|| missing("seedCriteria.seedTime"))?,
                 season_pack_seed_time: from
                     .fields
                     .iter()
                     .find_map(|f| match f {
-                        Field::SeasonPackSeedTime { value } => Some(value.map(|secs| Duration::from_secs(secs * 60))),
+                        Field::SeasonPackSeedTime { value } => {
+                            Some(value.map(|secs| Duration::from_secs(secs * 60)))
+                        }
                         _ => None,
                     })
-                    .expect("seedCriteria.seasonPackSeedTime field should always be present for torrent indexers"),
+                    .ok_or_else(|| missing("seedCriteria.seasonPackSeedTime"))?,
             },
         };
 
-        SonarrIndexer {
+        Ok(SonarrIndexer {
             id: from.id,
             name: from.name,
             api_key,
@@ -195,7 +312,10 @@ impl std::convert::From<SonarrIndexerSchema> for SonarrIndexer {
             implementation: from.implementation,
             config_contract: from.config_contract,
             protocol,
-        }
+            enable_rss: from.enable_rss,
+            enable_automatic_search: from.enable_automatic_search,
+            enable_interactive_search: from.enable_interactive_search,
+        })
     }
 }
 
@@ -248,9 +368,9 @@ impl std::convert::From<SonarrIndexer> for SonarrIndexerSchema {
         SonarrIndexerSchema {
             id: from.id,
             config_contract: from.config_contract,
-            enable_automatic_search: true,
-            enable_interactive_search: true,
-            enable_rss: true,
+            enable_automatic_search: from.enable_automatic_search,
+            enable_interactive_search: from.enable_interactive_search,
+            enable_rss: from.enable_rss,
             implementation: from.implementation,
             name: from.name,
             supports_rss: true,
@@ -262,6 +382,31 @@ impl std::convert::From<SonarrIndexer> for SonarrIndexerSchema {
     }
 }
 
+/// Noted in the name of an indexer we turned off because it failed Sonarr's test, so that we know
+/// to turn it back on once it passes
+const DISABLED_MARKER: &str = " (disabled by indexer-sync)";
+
+impl SonarrIndexer {
+    /// Turn RSS and both kinds of search on or off together
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enable_rss = enabled;
+        self.enable_automatic_search = enabled;
+        self.enable_interactive_search = enabled;
+    }
+
+    /// Turn the indexer off after it failed Sonarr's test, marking it as turned off by us rather
+    /// than by someone in Sonarr
+    fn mark_disabled(&mut self) {
+        self.set_enabled(false);
+        let at = self.name.rfind(" {").unwrap_or_else(|| self.name.len());
+        self.name.insert_str(at, DISABLED_MARKER);
+    }
+
+    fn disabled_by_sync(&self) -> bool {
+        self.name.contains(DISABLED_MARKER)
+    }
+}
+
 fn default_url() -> Url {
     Url::parse("http://example.com").unwrap()
 }
@@ -325,21 +470,58 @@ enum Field {
 }
 
 impl SonarrIndexer {
-    async fn save(&mut self, target: &Sonarr) -> Result<(), Box<dyn std::error::Error>> {
-        let (method, path) = if let Some(id) = &self.id {
+    /// Ask Sonarr to test the indexer as it would be saved, returning any validation failures
+    async fn test(&self, target: &Sonarr) -> Result<Vec<ValidationFailure>> {
+        // Testing doesn't change anything in Sonarr, so it's safe to retry
+        let response = target
+            .retry
+            .send(
+                target.upstream(),
+                target
+                    .client
+                    .post(target.url.join("api/v3/indexer/test")?)
+                    .json(&self),
+            )
+            .await?;
+
+        log::debug!(
+            "    -> POST api/v3/indexer/test ({}) - {}",
+            &self.name,
+            response.status()
+        );
+
+        match response.status() {
+            status if status.is_success() => Ok(vec![]),
+            reqwest::StatusCode::BAD_REQUEST => validation_failures(&self.name, response).await,
+            _ => check_status(response).map(|_| vec![]),
+        }
+    }
+
+    async fn save(&mut self, target: &Sonarr, force: bool) -> Result<()> {
+        let (method, mut path) = if let Some(id) = &self.id {
             log::info!("Updating {} in Sonarr (id: {})", &self.name, &id);
-            (reqwest::Method::PUT, format!("/api/v3/indexer/{}", &id))
+            (reqwest::Method::PUT, format!("api/v3/indexer/{}", &id))
         } else {
             log::info!("Creating {} in Sonarr", &self.name);
-            (reqwest::Method::POST, "/api/v3/indexer".to_owned())
+            (reqwest::Method::POST, "api/v3/indexer".to_owned())
         };
 
-        let response: reqwest::Response = target
+        if force {
+            path.push_str("?forceSave=true");
+        }
+
+        let request = target
             .client
             .request(method.clone(), target.url.join(&path)?)
-            .json(&self)
-            .send()
-            .await?;
+            .json(&self);
+        let response = if method == reqwest::Method::POST {
+            match self.create(target, request).await? {
+                Some(response) => response,
+                None => return Ok(()),
+            }
+        } else {
+            target.retry.send(target.upstream(), request).await?
+        };
 
         log::debug!(
             "    -> {} {} ({}) - {}",
@@ -348,85 +530,243 @@ impl SonarrIndexer {
             &self.name,
             response.status()
         );
-        log::debug!("    -> TV categories: {:?}", &self.categories);
-        log::debug!("    -> Anime categories: {:?}", &self.anime_categories);
+        log::debug!(
+            "    -> {} TV categories: {:?}",
+            &self.name,
+            &self.categories
+        );
+        log::debug!(
+            "    -> {} anime categories: {:?}",
+            &self.name,
+            &self.anime_categories
+        );
 
         match response.status() {
             status if status.is_success() => {
                 if status == reqwest::StatusCode::CREATED || status == reqwest::StatusCode::ACCEPTED
                 {
                     *self = response.json().await?;
-                    log::debug!("    <- {}", &self.id.unwrap());
+                    log::debug!("    <- {} ({:?})", &self.name, &self.id);
                 }
                 Ok(())
             }
-            status if status.is_client_error() => {
-                let response = response.text().await?;
-                log::debug!("    <- {}", &response);
+            reqwest::StatusCode::BAD_REQUEST => Err(Error::Validation {
+                indexer: self.name.clone(),
+                failures: validation_failures(&self.name, response).await?,
+            }),
+            _ => check_status(response).map(|_| ()),
+        }
+    }
 
-                let response = serde_json::from_str::<serde_json::Value>(&response)?;
-                log::error!("    <- {}", response[0]["errorMessage"].as_str().unwrap());
+    /// Send a request creating the indexer. A failed attempt may have created it anyway, so we
+    /// look for it in Sonarr before trying again. Returns `None` if it turned up there.
+    async fn create(
+        &mut self,
+        target: &Sonarr,
+        request: reqwest::RequestBuilder,
+    ) -> Result<Option<reqwest::Response>> {
+        let mut attempt = 0;
+        loop {
+            let result = match target.retry.attempt(target.upstream(), &request) {
+                Some(result) => result.await,
+                None => {
+                    return Ok(Some(
+                        target.retry.send_once(target.upstream(), request).await?,
+                    ))
+                }
+            };
+
+            let delay = match target.retry.retry_delay(&result, attempt) {
+                Some(delay) => delay,
+                None => return Ok(Some(result?)),
+            };
 
-                Err(Box::new(crate::Error("Save rejected".to_owned()))
-                    as Box<dyn std::error::Error>)
+            if http::may_have_been_processed(&result) {
+                let existing = target.existing_indexers().await?;
+                if let Some(created) = existing.into_iter().find(|i| i.name == self.name) {
+                    log::info!(
+                        "{} was created in Sonarr despite the error (id: {:?})",
+                        &self.name,
+                        &created.id
+                    );
+                    *self = created;
+                    return Ok(None);
+                }
             }
-            _ => Ok(response.error_for_status().map(|_| ())?),
+
+            http::log_retry(&result, delay, attempt + 1, target.retry.retries);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn delete(&self, target: &Sonarr) -> Result<()> {
+        let id = self
+            .id
+            .ok_or_else(|| Error::Schema(format!("{} has no ID", &self.name)))?;
+        log::info!("Pruning {} from Sonarr (id: {})", &self.name, &id);
+
+        let path = format!("api/v3/indexer/{}", &id);
+        let response = target
+            .retry
+            .send(
+                target.upstream(),
+                target.client.delete(target.url.join(&path)?),
+            )
+            .await?;
+        log::debug!(
+            "    -> DELETE {} ({}) - {}",
+            &path,
+            &self.name,
+            response.status()
+        );
+
+        // If an earlier attempt timed out after all, the indexer will already be gone
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(());
         }
+
+        check_status(response).map(|_| ())
     }
 }
+
+/// The `{jackett:id}` or `{jackett@instance:id}` suffix which marks an indexer as one we manage,
+/// if it has one. Anything else in braces was put there by someone else.
+fn managed_tag(name: &str) -> Option<&str> {
+    let tag = &name[name.rfind(" {")? + 1..];
+    let (source, id) = tag.strip_prefix('{')?.strip_suffix('}')?.split_once(':')?;
+    let ours = source == "jackett"
+        || matches!(source.strip_prefix("jackett@"), Some(instance) if is_valid_name(instance));
+    if ours && !id.is_empty() && !id.contains(char::is_whitespace) {
+        Some(tag)
+    } else {
+        None
+    }
+}
+
 struct IndexerSchemas(Vec<SonarrIndexer>);
 
-impl<'a> IndexerSchemas {
-    fn find(&'a self, kind: Implementation) -> &SonarrIndexer {
+impl IndexerSchemas {
+    fn find(&self, kind: Implementation) -> Result<&SonarrIndexer> {
         let Self(schemas) = self;
         schemas
             .iter()
             .find(|schema| schema.implementation == kind)
-            .unwrap_or_else(|| panic!("A schema of type {:?} is expected", kind))
+            .ok_or_else(|| Error::Schema(format!("Sonarr has no {:?} indexer schema", kind)))
     }
 }
 
+/// Turn error statuses into errors, telling a rejected API key apart from other failures
+/// Read the validation failures from a 400 response to testing or saving an indexer
+async fn validation_failures(
+    name: &str,
+    response: reqwest::Response,
+) -> Result<Vec<ValidationFailure>> {
+    let status = response.status();
+    let body = response.text().await?;
+    log::debug!("    <- {} - {}", name, &body);
+    Ok(parse_validation_failures(status, &body))
+}
+
+/// Validation errors come back as a list of failures, but the body may be something else we
+/// don't understand, in which case it becomes the one failure
+fn parse_validation_failures(status: reqwest::StatusCode, body: &str) -> Vec<ValidationFailure> {
+    serde_json::from_str(body).unwrap_or_else(|_| {
+        vec![ValidationFailure {
+            property_name: String::new(),
+            error_message: format!("{} {}", status, body),
+            is_warning: false,
+        }]
+    })
+}
+
+fn check_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    match response.status() {
+        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+            Err(Error::Auth("Sonarr rejected the API key".to_owned()))
+        }
+        _ => Ok(response.error_for_status()?),
+    }
+}
+
+/// Parse each indexer from a Sonarr listing separately, so that one we can't make sense of
+/// doesn't prevent syncing the rest
+fn parse_indexers(values: Vec<serde_json::Value>) -> Vec<SonarrIndexer> {
+    values
+        .into_iter()
+        .filter_map(|value| {
+            serde_json::from_value::<SonarrIndexer>(value)
+                .map_err(|e| log::warn!("Ignoring indexer from Sonarr: {}", e))
+                .ok()
+        })
+        // Filter out indexers we don't know anything about
+        .filter(|i| i.config_contract != ConfigContract::Other)
+        .collect()
+}
+
 impl Sonarr {
-    async fn schemas(&self) -> Result<IndexerSchemas, Box<dyn std::error::Error>> {
-        let schemas: Vec<SonarrIndexer> = self
-            .client
-            .get(self.url.join("/api/v3/indexer/schema")?)
-            .send()
-            .await?
-            .json()
-            .await?;
+    fn upstream(&self) -> Upstream<'_> {
+        Upstream {
+            service: "sonarr",
+            name: &self.name,
+            client: &self.client,
+        }
+    }
 
-        // Filter out schemas we don't know anything about
-        let schemas = schemas
-            .into_iter()
-            .filter(|i| i.config_contract != ConfigContract::Other)
-            .collect();
+    async fn schemas(&self) -> Result<IndexerSchemas> {
+        let response = self
+            .retry
+            .send(
+                self.upstream(),
+                self.client.get(self.url.join("api/v3/indexer/schema")?),
+            )
+            .await?;
+        let schemas = parse_indexers(check_status(response)?.json().await?);
 
         log::trace!("Fetched indexer schemas {:?}", schemas);
 
         Ok(IndexerSchemas(schemas))
     }
 
-    async fn existing_indexers(&self) -> Result<Vec<SonarrIndexer>, Box<dyn std::error::Error>> {
-        let indexers: Vec<SonarrIndexer> = self
-            .client
-            .get(self.url.join("/api/v3/indexer")?)
-            .send()
-            .await?
-            .json()
+    async fn existing_indexers(&self) -> Result<Vec<SonarrIndexer>> {
+        let response = self
+            .retry
+            .send(
+                self.upstream(),
+                self.client.get(self.url.join("api/v3/indexer")?),
+            )
             .await?;
-
-        // Filter out schemas we don't know anything about
-        let indexers = indexers
-            .into_iter()
-            .filter(|i| i.config_contract != ConfigContract::Other)
-            .collect();
+        let indexers = parse_indexers(check_status(response)?.json().await?);
 
         log::trace!("Fetched existing indexers {:?}", indexers);
 
         Ok(indexers)
     }
 
+    /// What this destination is called in reports
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Only use these of each indexer's TV categories
+    pub fn categories(mut self, categories: Option<Vec<usize>>) -> Self {
+        self.categories = categories;
+        self
+    }
+
+    /// Only use these of each indexer's anime categories
+    pub fn anime_categories(mut self, categories: Option<Vec<usize>>) -> Self {
+        self.anime_categories = categories;
+        self
+    }
+
+    /// Rewrite feed URLs, so that they point to where Sonarr can reach them
+    pub fn url_rewrites(mut self, rewrites: Vec<UrlRewrite>) -> Self {
+        self.url_rewrites = rewrites;
+        self
+    }
+
     pub fn private_seed_criteria(mut self, criteria: SeedCriteria) -> Self {
         self.private_seed_criteria = criteria;
         self
@@ -437,129 +777,365 @@ impl Sonarr {
         self
     }
 
+    pub fn protocol_preference(mut self, preference: Vec<FeedProtocol>) -> Self {
+        self.protocol_preference = preference;
+        self
+    }
+
+    pub fn test_failure_policy(mut self, policy: TestFailurePolicy) -> Self {
+        self.test_failure_policy = policy;
+        self
+    }
+
+    /// Save indexers for which Sonarr's test only produced warnings
+    pub fn force_save(mut self, force: bool) -> Self {
+        self.force_save = force;
+        self
+    }
+
+    /// Remove indexers which we previously synced but which are no longer provided
+    pub fn prune(mut self, prune: bool) -> Self {
+        self.prune = prune;
+        self
+    }
+
+    /// How many indexers to test and save at once
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// How failed requests to Sonarr are retried
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
     pub async fn update_indexers(
         self,
-        indexers: &[crate::Indexer],
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        indexers: Vec<&crate::Indexer>,
+    ) -> Result<DestinationReport> {
+        let mut report = DestinationReport::new(&self.name);
         let schemas = self.schemas().await?;
-        let mut existing_indexers = self.existing_indexers().await?;
-
-        for indexer in indexers {
-            log::trace!("Processing {:?}", indexer);
-
-            let mut new_indexer;
-            let mut sonarr_indexer: &mut SonarrIndexer;
-
-            let existing_indexer = existing_indexers.iter_mut().find(|i| {
-                let does_match = i
-                    .name
-                    .contains(&format!("{{{}}}", indexer.source.name_id()));
-                log::trace!("Checking if it matches {:?}: {}", i.name, does_match);
-                does_match
-            });
-
-            match indexer.urls {
-                FeedUrls {
-                    newznab: Some(ref feed),
-                    ..
-                } => {
-                    let existing_indexer =
-                        existing_indexer.filter(|i| i.implementation == Implementation::Newznab);
-
-                    if let Some(existing_indexer) = existing_indexer {
-                        sonarr_indexer = existing_indexer
-                    } else {
-                        new_indexer = schemas.find(Implementation::Newznab).clone();
-                        sonarr_indexer = &mut new_indexer;
-                    };
-
-                    sonarr_indexer.api_key = feed.api_key.as_deref().unwrap_or("").to_owned();
-                    sonarr_indexer.url = feed.url.to_owned();
-                    sonarr_indexer.categories = feed.capabilities.series().ids();
-                    sonarr_indexer.anime_categories = feed.capabilities.anime().ids();
+        let existing_indexers = self.existing_indexers().await?;
+
+        // Work out what every indexer should look like up front, so that the slow part (testing
+        // and saving each one) can happen concurrently.
+        let pending: Vec<_> = indexers
+            .iter()
+            .map(|indexer| {
+                log::trace!("Processing {:?}", indexer);
+
+                let mut entry = IndexerReport::new(indexer.source.name_id(), &indexer.name);
+                let candidate = self
+                    .prepare(indexer, &schemas, &existing_indexers, &mut entry)
+                    .unwrap_or_else(|e| {
+                        entry.outcome = Outcome::Failed(e.to_string());
+                        None
+                    });
+                (entry, candidate)
+            })
+            .collect();
+
+        let this = &self;
+        let entries: Vec<_> = stream::iter(pending)
+            .map(|(mut entry, candidate)| async move {
+                // A problem with one indexer shouldn't stop the others from syncing
+                if let Some(candidate) = candidate {
+                    entry.outcome = this
+                        .save_indexer(candidate)
+                        .await
+                        .unwrap_or_else(|e| Outcome::Failed(e.to_string()));
                 }
-                FeedUrls {
-                    torznab: Some(ref feed),
-                    ..
-                } => {
-                    let existing_indexer =
-                        existing_indexer.filter(|i| i.implementation == Implementation::Torznab);
-
-                    if let Some(existing_indexer) = existing_indexer {
-                        sonarr_indexer = existing_indexer;
-                    } else {
-                        new_indexer = schemas.find(Implementation::Torznab).clone();
-                        sonarr_indexer = &mut new_indexer;
+                entry
+            })
+            .buffered(self.concurrency)
+            .collect()
+            .await;
+
+        for entry in entries {
+            report.record(entry);
+        }
+
+        if self.prune {
+            let synced: Vec<_> = indexers
+                .iter()
+                .map(|i| format!("{{{}}}", i.source.name_id()))
+                .collect();
+
+            for existing in &existing_indexers {
+                match managed_tag(&existing.name) {
+                    Some(tag) if !synced.iter().any(|s| s == tag) => {
+                        let id = tag.trim_start_matches('{').trim_end_matches('}');
+                        let mut entry = IndexerReport::new(id, &existing.name);
+                        entry.outcome = match existing.delete(&self).await {
+                            Ok(()) => Outcome::Pruned,
+                            Err(e) => Outcome::Failed(format!("unable to prune: {}", e)),
+                        };
+                        report.record(entry);
                     }
+                    _ => {}
+                }
+            }
+        }
 
-                    sonarr_indexer.api_key = feed.api_key.as_deref().unwrap_or("").to_owned();
-                    sonarr_indexer.url = feed.url.to_owned();
-                    sonarr_indexer.categories = feed.capabilities.series().ids();
-                    sonarr_indexer.anime_categories = feed.capabilities.anime().ids();
-                    match sonarr_indexer.protocol {
-                        ConfiguredProtocol::Torrent {
+        Ok(report)
+    }
+
+    /// Work out what an indexer should look like in Sonarr. Returns `None` (having recorded why
+    /// in `entry`) if there is nothing to save.
+    fn prepare<'a>(
+        &self,
+        indexer: &crate::Indexer,
+        schemas: &IndexerSchemas,
+        existing_indexers: &'a [SonarrIndexer],
+        entry: &mut IndexerReport,
+    ) -> Result<Option<Candidate<'a>>> {
+        let available = indexer.urls.available();
+        let (protocol, feed) = match indexer.urls.preferred(&self.protocol_preference) {
+            Some(preferred) => preferred,
+            None => {
+                entry.outcome = Outcome::Skipped(format!(
+                    "offers {:?} but Sonarr is configured to use {:?}",
+                    &available, &self.protocol_preference
+                ));
+                return Ok(None);
+            }
+        };
+
+        entry.protocol = Some(protocol);
+
+        if available.contains(&FeedProtocol::Newznab) && available.contains(&FeedProtocol::Torznab)
+        {
+            log::info!(
+                "{} offers both Newznab and Torznab feeds; using {} as per protocol preference",
+                &indexer.name,
+                protocol
+            );
+        }
+
+        let kind = match feed {
+            Feed::Newznab(_) => Implementation::Newznab,
+            Feed::Torznab(_) => Implementation::Torznab,
+            Feed::Rss(_) => Implementation::TorrentRssIndexer,
+        };
+
+        let tag = format!("{{{}}}", indexer.source.name_id());
+        let existing_indexer = existing_indexers.iter().find(|i| {
+            let does_match = managed_tag(&i.name) == Some(tag.as_str());
+            log::trace!("Checking if it matches {:?}: {}", i.name, does_match);
+            does_match
+        });
+
+        // Indexers (or kinds of search) which have been turned off in Sonarr stay that way, unless
+        // we turned them off for failing the test, in which case they are tested again. An
+        // indexer can't be changed to another kind, so one which now uses another protocol is
+        // replaced with a new one.
+        let (mut sonarr_indexer, replaces) = match existing_indexer {
+            Some(existing_indexer) if existing_indexer.implementation == kind => {
+                (existing_indexer.clone(), None)
+            }
+            Some(existing_indexer) => {
+                let mut schema = schemas.find(kind)?.clone();
+                schema.enable_rss = existing_indexer.enable_rss;
+                schema.enable_automatic_search = existing_indexer.enable_automatic_search;
+                schema.enable_interactive_search = existing_indexer.enable_interactive_search;
+                (schema, Some(existing_indexer))
+            }
+            None => {
+                let mut schema = schemas.find(kind)?.clone();
+                schema.set_enabled(true);
+                (schema, None)
+            }
+        };
+        if matches!(existing_indexer, Some(e) if e.disabled_by_sync()) {
+            sonarr_indexer.set_enabled(true);
+        }
+
+        match feed {
+            Feed::Newznab(feed) => {
+                sonarr_indexer.api_key = feed.api_key.as_deref().unwrap_or("").to_owned();
+                sonarr_indexer.url = feed.url.to_owned();
+                sonarr_indexer.categories = feed.capabilities.series().ids();
+                sonarr_indexer.anime_categories = feed.capabilities.anime().ids();
+            }
+            Feed::Torznab(feed) => {
+                sonarr_indexer.api_key = feed.api_key.as_deref().unwrap_or("").to_owned();
+                sonarr_indexer.url = feed.url.to_owned();
+                sonarr_indexer.categories = feed.capabilities.series().ids();
+                sonarr_indexer.anime_categories = feed.capabilities.anime().ids();
+                match sonarr_indexer.protocol {
+                    ConfiguredProtocol::Torrent {
+                        minimum_seeders,
+                        seed_ratio,
+                        seed_time,
+                        season_pack_seed_time,
+                    } => {
+                        let criteria = if indexer.privacy == crate::IndexerPrivacy::Public {
+                            &self.public_seed_criteria
+                        } else {
+                            &self.private_seed_criteria
+                        };
+
+                        sonarr_indexer.protocol = ConfiguredProtocol::Torrent {
                             minimum_seeders,
-                            seed_ratio,
-                            seed_time,
-                            season_pack_seed_time,
-                        } => {
-                            let criteria = if indexer.privacy == crate::IndexerPrivacy::Public {
-                                &self.public_seed_criteria
-                            } else {
-                                &self.private_seed_criteria
-                            };
-
-                            sonarr_indexer.protocol = ConfiguredProtocol::Torrent {
-                                minimum_seeders,
-                                seed_ratio: criteria.seed_ratio.or(seed_ratio),
-                                seed_time: criteria.seed_time.or(seed_time),
-                                season_pack_seed_time: criteria
-                                    .season_pack_seed_time
-                                    .or(season_pack_seed_time),
-                            }
+                            seed_ratio: criteria.seed_ratio.or(seed_ratio),
+                            seed_time: criteria.seed_time.or(seed_time),
+                            season_pack_seed_time: criteria
+                                .season_pack_seed_time
+                                .or(season_pack_seed_time),
                         }
-                        _ => panic!("womp womp"),
                     }
-                }
-                FeedUrls {
-                    rss: Some(ref feed),
-                    ..
-                } => {
-                    let existing_indexer = existing_indexer
-                        .filter(|i| i.implementation == Implementation::TorrentRssIndexer);
-
-                    if let Some(existing_indexer) = existing_indexer {
-                        sonarr_indexer = existing_indexer;
-                    } else {
-                        new_indexer = schemas.find(Implementation::TorrentRssIndexer).clone();
-                        sonarr_indexer = &mut new_indexer;
+                    ConfiguredProtocol::Usenet => {
+                        return Err(Error::Schema(format!(
+                            "{} is a Torznab indexer but is not configured for torrents",
+                            &sonarr_indexer.name
+                        )))
                     }
-
-                    sonarr_indexer.url = feed.0.to_owned();
-                }
-                _ => {
-                    continue;
                 }
             }
+            Feed::Rss(feed) => {
+                sonarr_indexer.url = feed.0.to_owned();
+            }
+        }
 
-            sonarr_indexer.name = format!("{} {{{}}}", indexer.name, indexer.source.name_id());
+        sonarr_indexer.url = rewrite_url(&self.url_rewrites, &sonarr_indexer.url);
 
-            // We want to proceed, even if save() returns an Err. For now, the
-            // error-handling (just logging) is inlined into the save() method.
-            // I'd like it to live here but unfortunately Reqwest's error won't
-            // give access to the response body, which has further error
-            // details. Eventually, I'll wrap my own error type in there and do
-            // some handling here.
-            let _ = sonarr_indexer.save(&self).await;
+        if let Some(ref allowed) = self.categories {
+            sonarr_indexer.categories.retain(|c| allowed.contains(c));
+        }
+        if let Some(ref allowed) = self.anime_categories {
+            sonarr_indexer
+                .anime_categories
+                .retain(|c| allowed.contains(c));
         }
 
-        Ok(())
+        sonarr_indexer.name = format!("{} {}", indexer.name, tag);
+        entry.categories = sonarr_indexer.categories.clone();
+        entry.anime_categories = sonarr_indexer.anime_categories.clone();
+
+        if existing_indexer == Some(&sonarr_indexer) {
+            log::debug!("{} is already up to date in Sonarr", &sonarr_indexer.name);
+            entry.outcome = Outcome::Unchanged;
+            return Ok(None);
+        }
+
+        Ok(Some(Candidate {
+            indexer: sonarr_indexer,
+            replaces,
+        }))
+    }
+
+    /// Test an indexer with Sonarr and then save it, as per the test failure policy
+    async fn save_indexer(&self, candidate: Candidate<'_>) -> Result<Outcome> {
+        let Candidate {
+            indexer: mut sonarr_indexer,
+            replaces,
+        } = candidate;
+        let outcome = if sonarr_indexer.id.is_some() || replaces.is_some() {
+            Outcome::Updated
+        } else {
+            Outcome::Created
+        };
+
+        let failures = sonarr_indexer.test(self).await?;
+        let (force, outcome) = if failures.is_empty() {
+            (false, outcome)
+        } else {
+            let warnings_only = failures.iter().all(|f| f.is_warning);
+            let rejection = Error::Validation {
+                indexer: sonarr_indexer.name.clone(),
+                failures,
+            };
+
+            if warnings_only && self.force_save {
+                log::warn!("{}; saving anyway", rejection);
+                (true, outcome)
+            } else if self.test_failure_policy == TestFailurePolicy::Disable {
+                log::warn!("{}; saving it disabled", rejection);
+                sonarr_indexer.mark_disabled();
+                (
+                    true,
+                    Outcome::Failed(format!("saved disabled: {}", rejection)),
+                )
+            } else {
+                return Err(rejection);
+            }
+        };
+
+        sonarr_indexer.save(self, force).await?;
+
+        // Only once its replacement is saved, so that there's always one indexer with its tag
+        if let Some(replaced) = replaces {
+            log::info!(
+                "Replacing {} (id: {:?}) with a {:?} indexer",
+                &replaced.name,
+                &replaced.id,
+                &sonarr_indexer.implementation
+            );
+            replaced.delete(self).await?;
+        }
+        Ok(outcome)
     }
 }
 
+/// An indexer to save in Sonarr, and the existing indexer with the same tag it replaces if that
+/// is of another kind
+struct Candidate<'a> {
+    indexer: SonarrIndexer,
+    replaces: Option<&'a SonarrIndexer>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::source::jackett;
+    use crate::{FeedUrls, IndexerPrivacy, Rss, SourceIndexer};
+
+    fn sonarr() -> Sonarr {
+        let client = http::client(
+            None,
+            &http::TlsConfig::default(),
+            None,
+            &http::Headers::default(),
+            None,
+        )
+        .unwrap();
+        new(
+            "http://localhost:8989".parse().unwrap(),
+            Some("key".to_owned()),
+            client,
+        )
+        .unwrap()
+    }
+
+    fn schemas() -> IndexerSchemas {
+        let schema_blob = include_str!("../../test/sonarr-schemas.json");
+        IndexerSchemas(serde_json::from_str(schema_blob).unwrap())
+    }
+
+    fn rss_indexer(id: &str, name: &str) -> crate::Indexer {
+        crate::Indexer {
+            source: SourceIndexer::Jackett {
+                instance: None,
+                indexer: jackett::Indexer {
+                    id: id.to_owned(),
+                    name: name.to_owned(),
+                    potato_enabled: false,
+                    privacy: IndexerPrivacy::Public,
+                    capabilities: vec![],
+                },
+            },
+            name: name.to_owned(),
+            urls: FeedUrls {
+                potato: None,
+                rss: Some(Rss("http://jackett/rss".parse().unwrap())),
+                torznab: None,
+                newznab: None,
+            },
+            privacy: IndexerPrivacy::Public,
+        }
+    }
 
     #[test]
     fn test_deserialize_sonarr_schemas() -> serde_json::Result<()> {
@@ -574,6 +1150,185 @@ mod tests {
         let schema_blob = include_str!("../../test/sonarr-indexers.json");
         let indexers = serde_json::from_str::<Vec<SonarrIndexer>>(schema_blob)?;
         assert_eq!(indexers[0].id, Some(1));
+
+        // Each kind of search which is turned off stays off when the indexer is saved again
+        let saved = serde_json::to_value(&indexers[2])?;
+        assert_eq!(saved["enableRss"], false);
+        assert_eq!(saved["enableAutomaticSearch"], false);
+        assert_eq!(saved["enableInteractiveSearch"], true);
         Ok(())
     }
+
+    #[test]
+    fn test_deserialize_sonarr_validation_failures() -> serde_json::Result<()> {
+        let failures = serde_json::from_str::<Vec<ValidationFailure>>(
+            r#"[
+                {"propertyName": "BaseUrl", "errorMessage": "Unable to connect to indexer", "severity": "error"},
+                {"propertyName": "", "errorMessage": "No RSS results", "isWarning": true, "severity": "warning"}
+            ]"#,
+        )?;
+        assert_eq!(
+            failures[0].to_string(),
+            "error (BaseUrl): Unable to connect to indexer"
+        );
+        assert_eq!(failures[1].to_string(), "warning: No RSS results");
+
+        let rejection = Error::Validation {
+            indexer: "RARBG {jackett:rarbg}".to_owned(),
+            failures,
+        };
+        assert_eq!(
+            rejection.to_string(),
+            "RARBG {jackett:rarbg} was rejected: error (BaseUrl): Unable to connect to indexer; warning: No RSS results"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_validation_failures() {
+        let status = reqwest::StatusCode::BAD_REQUEST;
+        let failures = parse_validation_failures(
+            status,
+            r#"[{"propertyName": "", "errorMessage": "No RSS results", "isWarning": true}]"#,
+        );
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].is_warning);
+
+        let failures = parse_validation_failures(status, r#"{"message": "Bad request"}"#);
+        assert_eq!(
+            failures[0].to_string(),
+            r#"error: 400 Bad Request {"message": "Bad request"}"#
+        );
+        assert!(!failures[0].is_warning);
+    }
+
+    #[test]
+    fn test_managed_tag() {
+        assert_eq!(
+            managed_tag("RARBG {jackett:rarbg}"),
+            Some("{jackett:rarbg}")
+        );
+        assert_eq!(
+            managed_tag("RARBG {jackett@vpn:rarbg}"),
+            Some("{jackett@vpn:rarbg}")
+        );
+        assert_eq!(managed_tag("My {fancy} indexer"), None);
+        assert_eq!(managed_tag("Manually added"), None);
+
+        // Other suffixes in braces belong to indexers someone else added
+        assert_eq!(managed_tag("Foo {backup}"), None);
+        assert_eq!(managed_tag("Foo {prowlarr:foo}"), None);
+        assert_eq!(managed_tag("Foo {jackett@a b:foo}"), None);
+        assert_eq!(managed_tag("Foo {jackett:}"), None);
+    }
+
+    #[test]
+    fn test_deserialize_torrent_indexer_without_seed_criteria() {
+        let result = serde_json::from_str::<SonarrIndexer>(
+            r#"{
+                "configContract": "TorznabSettings",
+                "enableAutomaticSearch": true,
+                "enableInteractiveSearch": true,
+                "enableRss": true,
+                "implementation": "Torznab",
+                "protocol": "torrent",
+                "name": "Broken",
+                "supportsRss": true,
+                "supportsSearch": true,
+                "fields": [{"name": "minimumSeeders", "value": 1}]
+            }"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_config_xml() {
+        let (url, api_key) =
+            parse_config_xml(include_str!("../../test/sonarr-config.xml")).unwrap();
+        assert_eq!(url.as_str(), "http://localhost:8989/sonarr/");
+        assert_eq!(api_key, "0123456789abcdef0123456789abcdef");
+
+        let (url, _) = parse_config_xml(
+            "<Config><ApiKey>abc</ApiKey><EnableSsl>True</EnableSsl><SslPort>443</SslPort>\
+             <BindAddress>10.0.0.2</BindAddress></Config>",
+        )
+        .unwrap();
+        assert_eq!(url.as_str(), "https://10.0.0.2/");
+
+        assert!(parse_config_xml("<Config><Port>8989</Port></Config>").is_err());
+    }
+
+    #[test]
+    fn test_prepare_replaces_indexer_of_another_kind() {
+        let sonarr = sonarr();
+        let indexer = rss_indexer("limetorrents", "LimeTorrents");
+        let tag = format!("{{{}}}", indexer.source.name_id());
+
+        // Last synced as a Torznab indexer, with RSS turned off in Sonarr since
+        let mut existing = schemas().find(Implementation::Torznab).unwrap().clone();
+        existing.id = Some(16);
+        existing.name = format!("LimeTorrents {}", tag);
+        existing.set_enabled(true);
+        existing.enable_rss = false;
+        let existing_indexers = vec![existing];
+
+        let mut entry = IndexerReport::new(indexer.source.name_id(), &indexer.name);
+        let candidate = sonarr
+            .prepare(&indexer, &schemas(), &existing_indexers, &mut entry)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            candidate.indexer.implementation,
+            Implementation::TorrentRssIndexer
+        );
+        assert_eq!(candidate.indexer.id, None);
+        assert_eq!(candidate.indexer.name, format!("LimeTorrents {}", tag));
+        assert!(!candidate.indexer.enable_rss);
+        assert!(candidate.indexer.enable_automatic_search);
+        assert_eq!(candidate.replaces.and_then(|i| i.id), Some(16));
+    }
+
+    #[test]
+    fn test_prepare_reenables_indexer_disabled_by_sync() {
+        let sonarr = sonarr();
+        let indexer = rss_indexer("limetorrents", "LimeTorrents");
+        let tag = format!("{{{}}}", indexer.source.name_id());
+        let mut entry = IndexerReport::new(indexer.source.name_id(), &indexer.name);
+
+        // The first run fails Sonarr's test, so the indexer is saved disabled
+        let mut candidate = sonarr
+            .prepare(&indexer, &schemas(), &[], &mut entry)
+            .unwrap()
+            .unwrap();
+        candidate.indexer.mark_disabled();
+        let mut saved = candidate.indexer;
+        saved.id = Some(16);
+        assert_eq!(
+            saved.name,
+            format!("LimeTorrents (disabled by indexer-sync) {}", tag)
+        );
+        assert_eq!(managed_tag(&saved.name), Some(tag.as_str()));
+        assert!(!saved.enable_rss && !saved.enable_automatic_search);
+
+        // The next run tests it again, and saves it enabled if it passes
+        let existing_indexers = vec![saved];
+        let candidate = sonarr
+            .prepare(&indexer, &schemas(), &existing_indexers, &mut entry)
+            .unwrap()
+            .unwrap();
+        assert_eq!(candidate.indexer.id, Some(16));
+        assert_eq!(candidate.indexer.name, format!("LimeTorrents {}", tag));
+        assert!(candidate.indexer.enable_rss && candidate.indexer.enable_automatic_search);
+        assert!(!candidate.indexer.disabled_by_sync());
+
+        // One turned off in Sonarr is left alone
+        let mut saved = candidate.indexer;
+        saved.set_enabled(false);
+        let existing_indexers = vec![saved];
+        let candidate = sonarr
+            .prepare(&indexer, &schemas(), &existing_indexers, &mut entry)
+            .unwrap();
+        assert!(candidate.is_none());
+        assert_eq!(entry.outcome, Outcome::Unchanged);
+    }
 }
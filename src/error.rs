@@ -1,11 +1,119 @@
-use std::fmt::{Display, Formatter, Result};
+use serde::Deserialize;
+use std::fmt::{self, Display, Formatter};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(Debug)]
-pub struct Error(pub String);
+pub enum Error {
+    /// A request could not be made, or was answered with an unexpected error status
+    Http(reqwest::Error),
+
+    /// A request through a Unix socket could not be made
+    Socket(crate::socket::SocketError),
+
+    /// Credentials were missing or were rejected by the service
+    Auth(String),
+
+    /// The destination refused to accept an indexer
+    Validation {
+        indexer: String,
+        failures: Vec<ValidationFailure>,
+    },
+
+    /// A service responded with data we did not expect or could not make sense of
+    Schema(String),
+
+    /// The provided options do not describe something we can sync
+    Config(String),
+}
+
+/// A single complaint from a *arr application's validation of an indexer
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationFailure {
+    #[serde(default)]
+    pub property_name: String,
+    pub error_message: String,
+    #[serde(default)]
+    pub is_warning: bool,
+}
+
+impl Display for ValidationFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let severity = if self.is_warning { "warning" } else { "error" };
+        if self.property_name.is_empty() {
+            write!(f, "{}: {}", severity, self.error_message)
+        } else {
+            write!(
+                f,
+                "{} ({}): {}",
+                severity, self.property_name, self.error_message
+            )
+        }
+    }
+}
 
 impl Display for Error {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        write!(f, "{}", self.0)
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(e) => write!(f, "HTTP error: {}", e),
+            Self::Socket(e) => write!(f, "HTTP error: {}", e),
+            Self::Auth(msg) => write!(f, "Authentication failed: {}", msg),
+            Self::Validation { indexer, failures } => {
+                write!(f, "{} was rejected", indexer)?;
+                for (i, failure) in failures.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { ":" } else { ";" }, failure)?;
+                }
+                Ok(())
+            }
+            Self::Schema(msg) => write!(f, "Unexpected response: {}", msg),
+            Self::Config(msg) => write!(f, "Invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(e) => Some(e),
+            Self::Socket(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<crate::socket::SocketError> for Error {
+    fn from(e: crate::socket::SocketError) -> Self {
+        Self::Socket(e)
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(e: url::ParseError) -> Self {
+        Self::Config(format!("invalid URL: {}", e))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Schema(e.to_string())
+    }
+}
+
+impl From<reqwest::header::InvalidHeaderName> for Error {
+    fn from(e: reqwest::header::InvalidHeaderName) -> Self {
+        Self::Config(format!("invalid header name: {}", e))
+    }
+}
+
+impl From<reqwest::header::InvalidHeaderValue> for Error {
+    fn from(e: reqwest::header::InvalidHeaderValue) -> Self {
+        Self::Config(format!("invalid header value: {}", e))
     }
 }
-impl std::error::Error for Error {}
@@ -0,0 +1,220 @@
+use regex::{Regex, RegexBuilder};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{FeedProtocol, Indexer, IndexerPrivacy};
+
+/// Which indexers to sync, either everywhere or to a single destination
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexerFilter {
+    /// Only sync indexers matching one of these, unless it is empty
+    pub include: Vec<Pattern>,
+    /// Never sync indexers matching any of these
+    pub exclude: Vec<Pattern>,
+    /// Only sync indexers with one of these kinds of privacy, unless it is empty
+    pub privacy: Vec<IndexerPrivacy>,
+    /// Only sync indexers offering at least one of these feeds, unless it is empty
+    pub protocols: Vec<FeedProtocol>,
+}
+
+impl IndexerFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.privacy.is_empty()
+            && self.protocols.is_empty()
+    }
+
+    /// The first `include` pattern the indexer matches, if any
+    pub fn included_by(&self, indexer: &Indexer) -> Option<&Pattern> {
+        self.include.iter().find(|pattern| pattern.matches(indexer))
+    }
+
+    pub fn is_selected(&self, indexer: &Indexer) -> bool {
+        (self.include.is_empty() || self.included_by(indexer).is_some())
+            && !self.exclude.iter().any(|pattern| pattern.matches(indexer))
+            && (self.privacy.is_empty() || self.privacy.contains(&indexer.privacy))
+            && (self.protocols.is_empty()
+                || indexer
+                    .urls
+                    .available()
+                    .iter()
+                    .any(|protocol| self.protocols.contains(protocol)))
+    }
+}
+
+/// A way of picking out indexers, matched case-insensitively against both their name and their
+/// ID in the source:
+///
+/// - `id:ID` matches an indexer's ID exactly ("id:rarbg", or "id:jackett@vpn:rarbg" to pick out
+///   one source's)
+/// - `/REGEX/` matches a regular expression
+/// - anything else matches indexers containing it
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Id(String),
+    Regex(Regex),
+    Substring(String),
+}
+
+impl Pattern {
+    pub fn matches(&self, indexer: &Indexer) -> bool {
+        let id = indexer.source.id();
+        let name_id = indexer.source.name_id();
+
+        match self {
+            Self::Id(expected) => {
+                expected.eq_ignore_ascii_case(id) || expected.eq_ignore_ascii_case(&name_id)
+            }
+            Self::Regex(regex) => {
+                regex.is_match(&indexer.name) || regex.is_match(id) || regex.is_match(&name_id)
+            }
+            Self::Substring(term) => {
+                indexer.name.to_lowercase().contains(term) || id.to_lowercase().contains(term)
+            }
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(id) = s.strip_prefix("id:") {
+            Ok(Self::Id(id.to_owned()))
+        } else if s.len() > 1 && s.starts_with('/') && s.ends_with('/') {
+            RegexBuilder::new(&s[1..s.len() - 1])
+                .case_insensitive(true)
+                .build()
+                .map(Self::Regex)
+                .map_err(|e| format!("Invalid regular expression {:?}: {}", s, e))
+        } else {
+            Ok(Self::Substring(s.to_lowercase()))
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "id:{}", id),
+            Self::Regex(regex) => write!(f, "/{}/", regex),
+            Self::Substring(term) => f.write_str(term),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::jackett;
+    use crate::{FeedUrls, Rss, SourceIndexer};
+
+    fn indexer(instance: Option<&str>, id: &str, name: &str) -> Indexer {
+        Indexer {
+            source: SourceIndexer::Jackett {
+                instance: instance.map(str::to_owned),
+                indexer: jackett::Indexer {
+                    id: id.to_owned(),
+                    name: name.to_owned(),
+                    potato_enabled: false,
+                    privacy: IndexerPrivacy::Public,
+                    capabilities: vec![],
+                },
+            },
+            name: name.to_owned(),
+            urls: FeedUrls {
+                potato: None,
+                rss: None,
+                torznab: None,
+                newznab: None,
+            },
+            privacy: IndexerPrivacy::Public,
+        }
+    }
+
+    fn pattern(s: &str) -> Pattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_patterns() {
+        let tpb = indexer(None, "thepiratebay", "The Pirate Bay");
+        let vpn_tpb = indexer(Some("vpn"), "thepiratebay", "The Pirate Bay");
+
+        assert!(pattern("pirate").matches(&tpb));
+        assert!(pattern("PIRATEBAY").matches(&tpb));
+        assert!(!pattern("rarbg").matches(&tpb));
+
+        assert!(pattern("id:thepiratebay").matches(&tpb));
+        assert!(!pattern("id:piratebay").matches(&tpb));
+        assert!(pattern("id:jackett@vpn:thepiratebay").matches(&vpn_tpb));
+        assert!(!pattern("id:jackett@vpn:thepiratebay").matches(&tpb));
+
+        assert!(pattern("/^the .* bay$/").matches(&tpb));
+        assert!(!pattern("/^pirate/").matches(&tpb));
+        assert!("/(/".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filter = IndexerFilter {
+            include: vec![pattern("/^the /"), pattern("rarbg")],
+            exclude: vec![pattern("id:1337x")],
+            ..IndexerFilter::default()
+        };
+
+        let tpb = indexer(None, "thepiratebay", "The Pirate Bay");
+        assert!(filter.is_selected(&tpb));
+        assert_eq!(
+            filter.included_by(&tpb).map(ToString::to_string),
+            Some("/^the /".to_owned())
+        );
+        assert!(filter.is_selected(&indexer(None, "rarbg", "RARBG")));
+        assert!(!filter.is_selected(&indexer(None, "1337x", "The 1337x")));
+        assert!(!filter.is_selected(&indexer(None, "eztv", "EZTV")));
+
+        assert!(IndexerFilter::default().is_selected(&tpb));
+    }
+
+    #[test]
+    fn test_privacy_and_protocols() {
+        let filter = IndexerFilter {
+            privacy: vec![IndexerPrivacy::Private, IndexerPrivacy::SemiPrivate],
+            protocols: vec![FeedProtocol::Torznab, FeedProtocol::Newznab],
+            ..IndexerFilter::default()
+        };
+
+        let mut public = indexer(None, "rarbg", "RARBG");
+        public.urls.rss = Some(Rss("http://jackett/rss".parse().unwrap()));
+        assert!(!filter.is_selected(&public));
+
+        let mut private = indexer(None, "privatehd", "PrivateHD");
+        private.privacy = IndexerPrivacy::Private;
+        private.urls.rss = Some(Rss("http://jackett/rss".parse().unwrap()));
+        assert!(!filter.is_selected(&private), "only offers RSS");
+
+        private.urls.torznab = Some(crate::Torznab {
+            api_key: None,
+            url: "http://jackett/torznab".parse().unwrap(),
+            capabilities: vec![],
+        });
+        assert!(filter.is_selected(&private));
+    }
+}
@@ -0,0 +1,632 @@
+use rand::Rng;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::{de, Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+use url::Url;
+
+use crate::socket::{SocketClient, UnixSocket};
+use crate::{Error, Result};
+
+/// How requests to sources and destinations are retried when they fail in a way which might not
+/// happen again (connection errors, timeouts, 429 and 5xx responses)
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RetryPolicy {
+    /// How many times a request is retried before giving up
+    pub retries: u32,
+    /// The delay before the first retry, which doubles for each retry after that
+    #[serde(deserialize_with = "crate::config::duration")]
+    pub delay: Duration,
+    /// The longest we wait between attempts, including when asked to wait with `Retry-After`
+    #[serde(deserialize_with = "crate::config::duration")]
+    pub max_delay: Duration,
+    /// How long each attempt may take
+    #[serde(deserialize_with = "crate::config::duration")]
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Send a request which is safe to repeat, retrying it as necessary
+    pub async fn send(&self, upstream: Upstream<'_>, request: RequestBuilder) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let result = match self.attempt(upstream, &request) {
+                Some(result) => result.await,
+                // The body can't be sent twice, so we only get one go
+                None => return self.send_once(upstream, request).await,
+            };
+
+            match self.retry_delay(&result, attempt) {
+                Some(delay) => {
+                    log_retry(&result, delay, attempt + 1, self.retries);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                None => return result,
+            }
+        }
+    }
+
+    /// Send a copy of the request once, so that callers which need to check what happened before
+    /// retrying can do so. Returns `None` if the request can't be copied.
+    pub fn attempt<'a>(
+        &self,
+        upstream: Upstream<'a>,
+        request: &RequestBuilder,
+    ) -> Option<impl std::future::Future<Output = Result<Response>> + 'a> {
+        request
+            .try_clone()
+            .map(|request| self.send_once(upstream, request))
+    }
+
+    /// Send a request once, without retrying it
+    pub fn send_once<'a>(
+        &self,
+        upstream: Upstream<'a>,
+        request: RequestBuilder,
+    ) -> impl std::future::Future<Output = Result<Response>> + 'a {
+        let request = request.timeout(self.timeout).build();
+        async move {
+            let started = Instant::now();
+            let result = match request {
+                Ok(request) => upstream.client.execute(request).await,
+                Err(e) => Err(e.into()),
+            };
+            crate::metrics::record_request(upstream, &result, started.elapsed());
+            result
+        }
+    }
+
+    /// How long to wait before retrying a request with this result, if it should be retried
+    pub fn retry_delay(&self, result: &Result<Response>, attempt: u32) -> Option<Duration> {
+        if attempt >= self.retries {
+            return None;
+        }
+
+        match result {
+            Err(Error::Http(e)) if e.is_connect() || e.is_timeout() || e.is_request() => {
+                Some(self.backoff(attempt))
+            }
+            Err(Error::Socket(_)) => Some(self.backoff(attempt)),
+            Err(_) => None,
+            Ok(response) if is_transient(response.status()) => Some(
+                retry_after(response)
+                    .map(|delay| delay.min(self.max_delay))
+                    .unwrap_or_else(|| self.backoff(attempt)),
+            ),
+            Ok(_) => None,
+        }
+    }
+
+    /// Exponential backoff with "equal jitter": somewhere between half and all of the delay
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        delay / 2 + delay.mul_f64(rand::thread_rng().gen_range(0.0..=0.5))
+    }
+}
+
+/// The source or destination a request is sent to, as it is labelled in metrics
+#[derive(Clone, Copy, Debug)]
+pub struct Upstream<'a> {
+    /// The kind of service ("jackett", "sonarr", etc)
+    pub service: &'static str,
+    /// The name of the source or destination
+    pub name: &'a str,
+    /// The client which reaches it
+    pub client: &'a Client,
+}
+
+/// Sends requests to a service, either over the network or through its Unix socket. Requests are
+/// built as usual with reqwest, with this client's headers.
+#[derive(Clone, Debug)]
+pub struct Client {
+    network: reqwest::Client,
+    socket: Option<SocketClient>,
+    headers: HeaderMap,
+}
+
+impl Client {
+    pub fn get(&self, url: Url) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post(&self, url: Url) -> RequestBuilder {
+        self.request(Method::POST, url)
+    }
+
+    pub fn delete(&self, url: Url) -> RequestBuilder {
+        self.request(Method::DELETE, url)
+    }
+
+    pub fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        self.network
+            .request(method, url)
+            .headers(self.headers.clone())
+    }
+
+    async fn execute(&self, request: reqwest::Request) -> Result<Response> {
+        match &self.socket {
+            Some(socket) => Ok(socket.execute(request).await?),
+            None => Ok(self.network.execute(request).await?),
+        }
+    }
+}
+
+/// Sets up a [`Client`] for a service
+pub struct ClientBuilder {
+    network: reqwest::ClientBuilder,
+    socket: Option<UnixSocket>,
+    headers: HeaderMap,
+    cookies: bool,
+}
+
+impl ClientBuilder {
+    /// Send this header with every request, replacing any other with the same name
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Keep cookies the service sets, and send them back with later requests
+    pub fn cookie_store(mut self) -> Self {
+        self.network = self.network.cookie_store(true);
+        self.cookies = true;
+        self
+    }
+
+    /// Ask for compressed responses over the network
+    pub fn gzip(mut self) -> Self {
+        self.network = self.network.gzip(true);
+        self
+    }
+
+    pub fn build(self) -> Result<Client> {
+        Ok(Client {
+            socket: self
+                .socket
+                .as_ref()
+                .map(|socket| SocketClient::new(socket, self.cookies))
+                .transpose()?,
+            network: self.network.build()?,
+            headers: self.headers,
+        })
+    }
+}
+
+/// Which TLS certificates to trust and present when connecting to a service
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TlsConfig {
+    /// PEM files of CA certificates to trust as well as the system's
+    pub ca_certs: Vec<PathBuf>,
+    /// A PEM file of the client certificate to present, for mutual TLS
+    pub client_cert: Option<PathBuf>,
+    /// A PEM file of the client certificate's private key, unless it is in `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Don't verify the service's certificate at all
+    pub insecure: bool,
+}
+
+impl TlsConfig {
+    /// Set up a client to connect as configured
+    pub fn apply(&self, mut builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder> {
+        for path in &self.ca_certs {
+            let certs = reqwest::Certificate::from_pem_bundle(&read(path)?)
+                .map_err(|e| invalid(path, &e))?;
+            if certs.is_empty() {
+                return Err(invalid(path, &"no certificates found"));
+            }
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert_path), key_path) => {
+                let cert = read(cert_path)?;
+                let key = match key_path {
+                    Some(path) => read(path)?,
+                    None => cert.clone(),
+                };
+                let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key)
+                    .map_err(|e| invalid(cert_path, &e))?;
+                builder = builder.identity(identity);
+            }
+            (None, Some(_)) => {
+                return Err(Error::Config(
+                    "a client key was given without a client certificate".to_owned(),
+                ))
+            }
+            (None, None) => {}
+        }
+
+        Ok(builder.danger_accept_invalid_certs(self.insecure))
+    }
+}
+
+/// How to reach a service, when it isn't as set by the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY`
+/// and `NO_PROXY` environment variables
+#[derive(Clone, Debug, PartialEq)]
+pub enum Proxy {
+    /// Connect directly, ignoring the environment ("none")
+    Direct,
+    /// Connect through an HTTP or SOCKS5 proxy ("socks5h://localhost:1080", etc)
+    Url(Url),
+}
+
+impl Proxy {
+    pub fn apply(&self, builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder> {
+        Ok(match self {
+            Self::Direct => builder.no_proxy(),
+            Self::Url(url) => builder.proxy(reqwest::Proxy::all(url.clone())?),
+        })
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("none") {
+            return Ok(Self::Direct);
+        }
+
+        let url = Url::parse(s).map_err(|e| format!("Invalid proxy {:?}: {}", s, e))?;
+        match url.scheme() {
+            "http" | "https" | "socks5" | "socks5h" => Ok(Self::Url(url)),
+            scheme => Err(format!(
+                "Unknown proxy scheme {:?} (expected http, https, socks5 or socks5h)",
+                scheme
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direct => f.write_str("none"),
+            Self::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Proxy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Shown in place of secrets in logs
+const REDACTED: &str = "<redacted>";
+
+/// Headers sent with every request to a service, such as a token for a reverse proxy in front of
+/// it. Their values are kept out of logs.
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Headers(BTreeMap<String, String>);
+
+impl Headers {
+    /// Add a header, replacing any other with the same name
+    pub fn insert(&mut self, header: Header) {
+        self.0
+            .retain(|name, _| !name.eq_ignore_ascii_case(&header.name));
+        self.0.insert(header.name, header.value);
+    }
+
+    fn to_header_map(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.0 {
+            let mut value = HeaderValue::from_str(value)?;
+            value.set_sensitive(true);
+            headers.insert(HeaderName::from_bytes(name.as_bytes())?, value);
+        }
+        Ok(headers)
+    }
+}
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.keys().map(|name| (name, REDACTED)))
+            .finish()
+    }
+}
+
+/// A header given on the command line ("NAME: VALUE")
+#[derive(Clone)]
+pub struct Header {
+    name: String,
+    value: String,
+}
+
+impl Header {
+    pub fn new(name: &str, value: &str) -> std::result::Result<Self, String> {
+        let name = name.trim();
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name {:?}", name))?;
+        HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("Invalid value for header {:?}", name))?;
+        Ok(Self {
+            name: name.to_owned(),
+            value: value.trim().to_owned(),
+        })
+    }
+}
+
+impl FromStr for Header {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| "Expected a header as NAME: VALUE".to_owned())?;
+        Self::new(name, value)
+    }
+}
+
+impl fmt::Debug for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, REDACTED)
+    }
+}
+
+/// Credentials for a reverse proxy in front of a service, sent with HTTP Basic authentication
+/// ("USERNAME:PASSWORD" on the command line)
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuth {
+    pub username: String,
+    #[serde(default, deserialize_with = "crate::secret::optional")]
+    pub password: Option<String>,
+}
+
+impl BasicAuth {
+    fn header_value(&self) -> Result<HeaderValue> {
+        let credentials = format!(
+            "{}:{}",
+            self.username,
+            self.password.as_deref().unwrap_or("")
+        );
+        let mut value = HeaderValue::from_str(&format!("Basic {}", base64::encode(credentials)))?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+
+impl FromStr for BasicAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (username, password) = match s.split_once(':') {
+            Some((username, password)) => (username, Some(password.to_owned())),
+            None => (s, None),
+        };
+        if username.is_empty() {
+            return Err("Expected credentials as USERNAME:PASSWORD".to_owned());
+        }
+        Ok(Self {
+            username: username.to_owned(),
+            password,
+        })
+    }
+}
+
+impl fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+/// A client for a service which connects as configured, through its Unix socket if it has one
+pub fn client(
+    socket: Option<&UnixSocket>,
+    tls: &TlsConfig,
+    proxy: Option<&Proxy>,
+    headers: &Headers,
+    basic_auth: Option<&BasicAuth>,
+) -> Result<ClientBuilder> {
+    let mut default_headers = headers.to_header_map()?;
+    if let Some(basic_auth) = basic_auth {
+        default_headers.insert(header::AUTHORIZATION, basic_auth.header_value()?);
+    }
+
+    let network = tls.apply(reqwest::Client::builder())?;
+    Ok(ClientBuilder {
+        network: match proxy {
+            Some(proxy) => proxy.apply(network)?,
+            None => network,
+        },
+        socket: socket.cloned(),
+        headers: default_headers,
+        cookies: false,
+    })
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| invalid(path, &e))
+}
+
+fn invalid(path: &Path, e: &dyn std::fmt::Display) -> Error {
+    Error::Config(format!("{}: {}", path.display(), e))
+}
+
+pub fn log_retry(result: &Result<Response>, delay: Duration, retry: u32, retries: u32) {
+    let problem = match result {
+        Ok(response) => format!("{} returned {}", response.url(), response.status()),
+        Err(e) => e.to_string(),
+    };
+    log::warn!(
+        "{}; retrying in {:.1}s ({}/{})",
+        problem,
+        delay.as_secs_f64(),
+        retry,
+        retries
+    );
+}
+
+/// Whether a failed request might still have been acted on by the server. A request which
+/// couldn't connect, or which was turned away with 429, certainly wasn't.
+pub fn may_have_been_processed(result: &Result<Response>) -> bool {
+    match result {
+        Err(Error::Http(e)) => !e.is_connect(),
+        Err(Error::Socket(e)) => !e.is_connect(),
+        Err(_) => true,
+        Ok(response) => response.status() != StatusCode::TOO_MANY_REQUESTS,
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+    parse_retry_after(value, SystemTime::now())
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()
+            .map(|date| date.duration_since(now).unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proxy() {
+        assert_eq!("none".parse(), Ok(Proxy::Direct));
+        assert_eq!(
+            "socks5h://localhost:1080"
+                .parse::<Proxy>()
+                .map(|p| p.to_string()),
+            Ok("socks5h://localhost:1080".to_owned())
+        );
+        assert!("http://proxy:3128".parse::<Proxy>().is_ok());
+        assert!("ftp://proxy".parse::<Proxy>().is_err());
+        assert!("proxy:3128".parse::<Proxy>().is_err());
+    }
+
+    #[test]
+    fn test_headers() -> Result<()> {
+        let mut headers = Headers::default();
+        headers.insert("X-Token: abc123".parse().unwrap());
+        headers.insert("x-token:  def456 ".parse().unwrap());
+        headers.insert("Remote-User: indexer-sync".parse().unwrap());
+
+        let map = headers.to_header_map()?;
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["x-token"], "def456");
+        assert!(map["x-token"].is_sensitive());
+        assert_eq!(
+            format!("{:?}", headers),
+            r#"{"Remote-User": "<redacted>", "x-token": "<redacted>"}"#
+        );
+
+        assert!("X-Token".parse::<Header>().is_err());
+        assert!("X Token: abc".parse::<Header>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_basic_auth() -> Result<()> {
+        let auth: BasicAuth = "aladdin:open sesame".parse().unwrap();
+        assert_eq!(auth.header_value()?, "Basic YWxhZGRpbjpvcGVuIHNlc2FtZQ==");
+        assert!(!format!("{:?}", auth).contains("sesame"));
+        assert!(":password".parse::<BasicAuth>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls_config() {
+        let tls = TlsConfig {
+            ca_certs: vec!["test/tls-cert.pem".into()],
+            client_cert: Some("test/tls-cert.pem".into()),
+            client_key: Some("test/tls-key.pem".into()),
+            insecure: false,
+        };
+        assert!(tls.apply(reqwest::Client::builder()).is_ok());
+
+        let missing = TlsConfig {
+            ca_certs: vec!["test/nonexistent.pem".into()],
+            ..TlsConfig::default()
+        };
+        assert!(missing.apply(reqwest::Client::builder()).is_err());
+
+        let key_only = TlsConfig {
+            client_key: Some("test/tls-key.pem".into()),
+            ..TlsConfig::default()
+        };
+        assert!(key_only.apply(reqwest::Client::builder()).is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            retries: 10,
+            delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(10),
+            ..RetryPolicy::default()
+        };
+
+        for (attempt, full) in [(0, 2), (1, 4), (2, 8), (3, 10), (9, 10)].iter() {
+            let full = Duration::from_secs(*full);
+            let delay = policy.backoff(*attempt);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:07 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
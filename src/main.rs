@@ -1,10 +1,24 @@
 use clap::{crate_authors, crate_version, ArgGroup, Clap};
-use serde::Deserialize;
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use url::Url;
 
+mod api;
+mod config;
 mod destination;
 mod error;
+mod filter;
+mod http;
+mod metrics;
+mod report;
+mod schedule;
+mod secret;
+mod socket;
 mod source;
 mod util;
 mod znab;
@@ -14,27 +28,346 @@ pub use error::*;
 use source::jackett;
 pub use znab::*;
 
-/// At least one {src} and at least one {dst} must be specified in order to sync.
+/// At least one {src} and at least one {dst} must be specified in order to sync, either as options
+/// or in the `--config` file.
 #[derive(Clap, Debug)]
 #[clap(
     version = crate_version!(),
     author = crate_authors!(),
-    group = ArgGroup::new("src").multiple(true).required(true),
-    group = ArgGroup::new("dst").multiple(true).required(true),
-    group = ArgGroup::new("tv").multiple(true),
+    group = ArgGroup::new("src").multiple(true),
+    group = ArgGroup::new("dst").multiple(true),
 )]
 struct Opts {
+    /// Read sources, destinations and settings from this TOML or YAML file
+    ///
+    /// Options given on the command line or in the environment take precedence over the file.
+    /// Any {src} or {dst} options are added to those in the file. If the file already has a source
+    /// or destination with the same name ("jackett", "sonarr" or the NAME given), only its URL is
+    /// replaced.
+    #[clap(short, long, value_name = "FILE", env = "SYNC_CONFIG")]
+    config: Option<PathBuf>,
+
     /// {src} Source indexers from this Jackett instance
     ///
-    /// Basic Auth credentials will be extracted and used as admin password.
-    #[clap(short = 'J', long, value_name = "URL", validator = util::is_http_url, env = "SYNC_JACKETT_URL", group = "src")]
-    jackett: Option<Url>,
+    /// Basic Auth credentials will be extracted and used as admin password. May be given several
+    /// times (or space-separated in the environment) to source indexers from several Jackett
+    /// instances, each named with NAME=URL ("vpn=http://...", etc). Otherwise, it is named
+    /// "jackett".
+    ///
+    /// The name is part of each indexer's identity in destinations ("{jackett@vpn:rarbg}"), so
+    /// the same tracker from two instances is kept apart. Indexers from an instance named "jackett"
+    /// keep the plain "{jackett:rarbg}" identity.
+    #[clap(
+        short = 'J',
+        long,
+        value_name = "[NAME=]URL",
+        env = "SYNC_JACKETT_URL",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        value_delimiter = " ",
+        group = "src"
+    )]
+    jackett: Vec<util::NamedUrl>,
+
+    /// The Jackett admin password, instead of giving it in the URL
+    ///
+    /// Used for every Jackett source. Like other credentials, this may be read from a file with
+    /// SYNC_JACKETT_PASSWORD_FILE.
+    #[clap(long, value_name = "PASSWORD", env = "SYNC_JACKETT_PASSWORD")]
+    jackett_password: Option<String>,
+
+    /// The URL destinations should use to reach Jackett's feeds, if it isn't the same as the one
+    /// given with `--jackett`
+    ///
+    /// For when we reach Jackett at one address ("http://localhost:9117") but destinations have to
+    /// use another ("http://jackett:9117"). With several Jackett instances, give each as NAME=URL
+    /// (space-separated in the environment).
+    #[clap(
+        long,
+        value_name = "[NAME=]URL",
+        env = "SYNC_JACKETT_FEED_URL",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        value_delimiter = " "
+    )]
+    jackett_feed_url: Vec<util::NamedUrl>,
+
+    /// Trust the CA certificates in this PEM file for Jackett, as well as the system's
+    ///
+    /// May be given several times (or space-separated in the environment).
+    #[clap(
+        long,
+        value_name = "FILE",
+        env = "SYNC_JACKETT_CA_CERT",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        value_delimiter = " "
+    )]
+    jackett_ca_cert: Vec<PathBuf>,
+
+    /// Present the client certificate in this PEM file to Jackett, for mutual TLS
+    ///
+    /// The certificate's private key may be in the same file, or given with `--jackett-client-key`.
+    #[clap(long, value_name = "FILE", env = "SYNC_JACKETT_CLIENT_CERT")]
+    jackett_client_cert: Option<PathBuf>,
+
+    /// The private key of `--jackett-client-cert`, as a PEM file
+    #[clap(
+        long,
+        value_name = "FILE",
+        env = "SYNC_JACKETT_CLIENT_KEY",
+        requires = "jackett-client-cert"
+    )]
+    jackett_client_key: Option<PathBuf>,
+
+    /// Don't verify Jackett's TLS certificate. This is insecure
+    #[clap(long)]
+    jackett_insecure: bool,
+
+    /// Connect to Jackett through this proxy ("socks5h://localhost:1080", "http://proxy:3128", etc)
+    ///
+    /// By default, the proxies given by the HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY
+    /// environment variables are used. "none" connects directly regardless.
+    #[clap(long, value_name = "URL", env = "SYNC_JACKETT_PROXY")]
+    jackett_proxy: Option<http::Proxy>,
+
+    /// Send this header with every request to Jackett ("X-Token: abc123", etc)
+    ///
+    /// For reverse proxies in front of Jackett which expect a token. May be given several times.
+    /// Header values are kept out of logs.
+    #[clap(
+        long,
+        value_name = "HEADER",
+        env = "SYNC_JACKETT_HEADER",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    jackett_header: Vec<http::Header>,
+
+    /// Log in to a reverse proxy in front of Jackett with these Basic Auth credentials
+    /// ("USERNAME:PASSWORD")
+    #[clap(long, value_name = "CREDENTIALS", env = "SYNC_JACKETT_BASIC_AUTH")]
+    jackett_basic_auth: Option<http::BasicAuth>,
 
     /// {dst} Sync indexers to this Sonarr instance
     ///
-    /// Encoded Basic Auth credentials will be extracted and used as the API token.
-    #[clap(short = 'S', long, value_name = "URL", validator = util::is_http_url, env = "SYNC_SONARR_URL", group = "dst", group = "tv")]
-    sonarr: Option<Url>,
+    /// Encoded Basic Auth credentials will be extracted and used as the API token. May be given
+    /// several times (or space-separated in the environment) to sync to several Sonarr instances,
+    /// each named with NAME=URL ("anime=http://...", etc). Otherwise, it is named "sonarr".
+    #[clap(
+        short = 'S',
+        long,
+        value_name = "[NAME=]URL",
+        env = "SYNC_SONARR_URL",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        value_delimiter = " ",
+        group = "dst"
+    )]
+    sonarr: Vec<util::NamedUrl>,
+
+    /// {dst} Sync indexers to the Sonarr which uses this config.xml
+    ///
+    /// The URL and API key are read from Sonarr's config file (its `Port`, `UrlBase`, `EnableSsl`
+    /// and `ApiKey` settings), for when indexer-sync runs alongside Sonarr with access to its data
+    /// directory. It is added as the destination named "sonarr", so a URL given for that with
+    /// `--sonarr` (or in the config file) is used instead of the one read from config.xml.
+    #[clap(long, value_name = "FILE", env = "SYNC_SONARR_CONFIG", group = "dst")]
+    sonarr_config: Option<PathBuf>,
+
+    /// The Sonarr API key, instead of giving it in the URL
+    ///
+    /// Used for every Sonarr destination. Like other credentials, this may be read from a file with
+    /// SYNC_SONARR_API_KEY_FILE.
+    #[clap(long, value_name = "KEY", env = "SYNC_SONARR_API_KEY")]
+    sonarr_api_key: Option<String>,
+
+    /// Run this shell command to fetch any credentials which aren't given as options or in the
+    /// config file, before falling back to those in URLs
+    ///
+    /// The command is given the name of the secret it should print as its first argument and as
+    /// SYNC_SECRET_NAME: "SOURCE/password" for Jackett and "DESTINATION/api-key" for Sonarr, with
+    /// the name of the source or destination ("jackett/password", "sonarr/api-key", etc). Printing
+    /// nothing means there is no such secret.
+    #[clap(long, value_name = "COMMAND", env = "SYNC_SECRET_COMMAND")]
+    secret_command: Option<String>,
+
+    /// Feed protocols to use in Sonarr, in order of preference ("torznab,newznab", etc)
+    ///
+    /// When an indexer offers several feeds, the first of these protocols which it supports is
+    /// used. Indexers supporting none of them are skipped, so leave out `rss` to never fall back to
+    /// RSS-only indexers (which Sonarr cannot search). Defaults to "newznab,torznab,rss".
+    #[clap(
+        long,
+        value_name = "PROTOCOLS",
+        env = "SYNC_SONARR_PROTOCOLS",
+        use_delimiter = true
+    )]
+    sonarr_protocols: Option<Vec<FeedProtocol>>,
+
+    /// What to do with indexers which fail Sonarr's indexer test ("skip" or "disable")
+    ///
+    /// Every indexer is tested by Sonarr before it is saved. Failing indexers are reported along
+    /// with Sonarr's validation messages and then either left out of Sonarr ("skip") or saved with
+    /// RSS and searching turned off ("disable"). Indexers saved disabled are marked as such in
+    /// their name and turned back on once they pass. Defaults to "skip".
+    #[clap(long, value_name = "POLICY", env = "SYNC_SONARR_TEST_FAILURE")]
+    sonarr_test_failure: Option<sonarr::TestFailurePolicy>,
+
+    /// Save indexers to Sonarr even when its indexer test produces warnings
+    ///
+    /// Without this, indexers with warnings are handled as per `--sonarr-test-failure`.
+    #[clap(long)]
+    sonarr_force_save: bool,
+
+    /// How many indexers to test and save in Sonarr at once
+    ///
+    /// Defaults to 4.
+    #[clap(long, value_name = "COUNT", env = "SYNC_SONARR_CONCURRENCY")]
+    sonarr_concurrency: Option<usize>,
+
+    /// Only use these TV categories in Sonarr ("5000,5030,5040", etc)
+    ///
+    /// Each indexer's supported TV categories are limited to those in this list. Defaults to all
+    /// of them.
+    #[clap(
+        long,
+        value_name = "CATEGORIES",
+        env = "SYNC_SONARR_CATEGORIES",
+        use_delimiter = true
+    )]
+    sonarr_categories: Option<Vec<usize>>,
+
+    /// Only use these anime categories in Sonarr ("5070", etc)
+    ///
+    /// Each indexer's supported anime categories are limited to those in this list. Defaults to
+    /// all of them.
+    #[clap(
+        long,
+        value_name = "CATEGORIES",
+        env = "SYNC_SONARR_ANIME_CATEGORIES",
+        use_delimiter = true
+    )]
+    sonarr_anime_categories: Option<Vec<usize>>,
+
+    /// Remove previously synced indexers from Sonarr when they are no longer being synced
+    ///
+    /// Only indexers created by indexer-sync (those with a `{jackett:ID}` or `{jackett@NAME:ID}`
+    /// suffix in their name) are removed. This includes indexers excluded by the INDEXERS filter.
+    #[clap(long)]
+    sonarr_prune: bool,
+
+    /// Rewrite the start of feed URLs given to Sonarr ("http://localhost:9117=http://jackett:9117")
+    ///
+    /// Feed URLs on FROM's scheme, host and port, and within its path, have that replaced with TO,
+    /// for when Sonarr reaches sources at other addresses than we do. The first matching rewrite is
+    /// used. May be given several times (or space-separated in the environment).
+    #[clap(
+        long,
+        value_name = "FROM=TO",
+        env = "SYNC_SONARR_REWRITE",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        value_delimiter = " "
+    )]
+    sonarr_rewrite: Vec<util::UrlRewrite>,
+
+    /// Trust the CA certificates in this PEM file for Sonarr, as well as the system's
+    ///
+    /// May be given several times (or space-separated in the environment).
+    #[clap(
+        long,
+        value_name = "FILE",
+        env = "SYNC_SONARR_CA_CERT",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        value_delimiter = " "
+    )]
+    sonarr_ca_cert: Vec<PathBuf>,
+
+    /// Present the client certificate in this PEM file to Sonarr, for mutual TLS
+    ///
+    /// The certificate's private key may be in the same file, or given with `--sonarr-client-key`.
+    #[clap(long, value_name = "FILE", env = "SYNC_SONARR_CLIENT_CERT")]
+    sonarr_client_cert: Option<PathBuf>,
+
+    /// The private key of `--sonarr-client-cert`, as a PEM file
+    #[clap(
+        long,
+        value_name = "FILE",
+        env = "SYNC_SONARR_CLIENT_KEY",
+        requires = "sonarr-client-cert"
+    )]
+    sonarr_client_key: Option<PathBuf>,
+
+    /// Don't verify Sonarr's TLS certificate. This is insecure
+    #[clap(long)]
+    sonarr_insecure: bool,
+
+    /// Connect to Sonarr through this proxy, like `--jackett-proxy`
+    #[clap(long, value_name = "URL", env = "SYNC_SONARR_PROXY")]
+    sonarr_proxy: Option<http::Proxy>,
+
+    /// Send this header with every request to Sonarr, like `--jackett-header`
+    #[clap(
+        long,
+        value_name = "HEADER",
+        env = "SYNC_SONARR_HEADER",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    sonarr_header: Vec<http::Header>,
+
+    /// Log in to a reverse proxy in front of Sonarr with these Basic Auth credentials
+    /// ("USERNAME:PASSWORD")
+    ///
+    /// These are separate from the API key, which is sent to Sonarr itself.
+    #[clap(long, value_name = "CREDENTIALS", env = "SYNC_SONARR_BASIC_AUTH")]
+    sonarr_basic_auth: Option<http::BasicAuth>,
+
+    /// When to exit with a failure status after syncing ("never", "any" or "all")
+    ///
+    /// With "any", failing to sync any indexer to any destination is a failure. With "all", it is
+    /// only a failure when a destination had failures and no indexers were synced to it. The exit
+    /// status is 2 in either case. Does not apply in polling mode. Defaults to "any".
+    #[clap(long, value_name = "POLICY", env = "SYNC_FAIL_ON")]
+    fail_on: Option<report::FailurePolicy>,
+
+    /// Write the outcome of each sync to stdout ("text", "json" or "yaml")
+    ///
+    /// "json" and "yaml" describe the discovered indexers, which were selected by INDEXERS, and
+    /// what was done with each in every destination. In polling mode, one document is written per
+    /// sync (one line per document for JSON). Logs are always written to stderr. Defaults to
+    /// "text".
+    #[clap(short, long, value_name = "FORMAT", env = "SYNC_OUTPUT")]
+    output: Option<report::OutputFormat>,
+
+    /// How many times to retry requests which fail due to connection problems, timeouts or
+    /// server errors (including 429 Too Many Requests)
+    ///
+    /// Defaults to 3.
+    #[clap(long, value_name = "COUNT", env = "SYNC_RETRIES")]
+    retries: Option<u32>,
+
+    /// How long to wait before the first retry ("1s", "500ms", etc)
+    ///
+    /// The delay doubles with each retry, with some randomness added, up to `--retry-max-delay`.
+    /// Servers asking us to wait with a `Retry-After` header are obliged, up to the same limit.
+    /// Defaults to 1 second.
+    #[clap(long, value_name = "DURATION", env = "SYNC_RETRY_DELAY", parse(try_from_str = parse_duration::parse::parse))]
+    retry_delay: Option<Duration>,
+
+    /// The longest to wait between retries
+    ///
+    /// Defaults to 1 minute.
+    #[clap(long, value_name = "DURATION", env = "SYNC_RETRY_MAX_DELAY", parse(try_from_str = parse_duration::parse::parse))]
+    retry_max_delay: Option<Duration>,
+
+    /// How long to wait for a response to each request before giving up on it
+    ///
+    /// Defaults to 1 minute.
+    #[clap(long, value_name = "DURATION", env = "SYNC_TIMEOUT", parse(try_from_str = parse_duration::parse::parse))]
+    timeout: Option<Duration>,
 
     /// Polling mode. Sync every DURATION ("1h", "3s", etc)
     ///
@@ -44,13 +377,80 @@ struct Opts {
     #[clap(short, long, value_name = "DURATION", env = "SYNC_INTERVAL", parse(try_from_str = parse_duration::parse::parse))]
     interval: Option<Duration>,
 
+    /// Exit polling mode after this many syncs in a row have failed
+    ///
+    /// A sync fails when a source or destination can't be reached or used at all (rather than
+    /// when individual indexers fail). By default, polling mode carries on regardless.
+    #[clap(long, value_name = "COUNT", env = "SYNC_MAX_CONSECUTIVE_FAILURES")]
+    max_consecutive_failures: Option<usize>,
+
+    /// Polling mode. Sync at the times given by a cron expression ("0 */6 * * *", etc)
+    ///
+    /// Standard 5-field expressions are interpreted in the local timezone. A leading seconds field
+    /// and trailing years field may also be given.
+    #[clap(long, value_name = "CRON", env = "SYNC_SCHEDULE", parse(try_from_str = schedule::parse_cron), conflicts_with = "interval")]
+    schedule: Option<cron::Schedule>,
+
+    /// Serve the control API on this address in polling mode ("127.0.0.1:8080", etc)
+    ///
+    /// `POST /sync` syncs straight away, optionally only the indexers matching `?indexer=INDEXER`
+    /// or only the destination given by `?destination=NAME`. `GET /status` describes the last
+    /// sync, when the next is due and the health of each destination, and `GET /indexers` lists the
+    /// indexers in every source and what each destination did with them. `GET /metrics` gives
+    /// Prometheus metrics. There is no authentication, so only listen on addresses that only
+    /// trusted clients can reach.
+    ///
+    /// Only used in polling mode (`--interval` or `--schedule`), so metrics are only recorded and
+    /// served there.
+    #[clap(long, value_name = "ADDRESS", env = "SYNC_LISTEN")]
+    listen: Option<SocketAddr>,
+
     /// Limit synced endexers to those matching these terms
     ///
     /// Provide indexers that you want to update. These values will be case-insensitively substring
-    /// matched against indexer/tracker names. Only those which match will be synced. If not
-    /// provided, all discovered indexers will be synced.
+    /// matched against indexer/tracker names and IDs. Only those which match will be synced. If not
+    /// provided, all discovered indexers will be synced. "id:ID" matches an indexer ID exactly
+    /// ("id:rarbg", "id:jackett@vpn:rarbg", etc) and "/REGEX/" matches a regular expression.
     #[clap(value_name = "INDEXERS")]
-    indexers_to_sync: Vec<String>,
+    indexers_to_sync: Vec<filter::Pattern>,
+
+    /// Never sync indexers matching this term
+    ///
+    /// Terms are matched in the same way as INDEXERS. May be given several times, or
+    /// comma-separated. Regular expressions containing commas can be given in the config file's
+    /// `filter` instead.
+    #[clap(
+        long,
+        value_name = "INDEXER",
+        env = "SYNC_EXCLUDE",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    exclude: Vec<filter::Pattern>,
+
+    /// Only sync indexers with these kinds of privacy ("private,semi-private", etc)
+    ///
+    /// Any of "public", "semi-private" and "private". Defaults to all of them.
+    #[clap(
+        long,
+        value_name = "PRIVACY",
+        env = "SYNC_PRIVACY",
+        use_delimiter = true
+    )]
+    privacy: Option<Vec<IndexerPrivacy>>,
+
+    /// Only sync indexers offering one of these feed protocols ("torznab,newznab", etc)
+    ///
+    /// Unlike `--sonarr-protocols`, indexers left out by this aren't synced at all (and so are
+    /// removed by `--sonarr-prune`). Defaults to any protocol.
+    #[clap(
+        long,
+        value_name = "PROTOCOLS",
+        env = "SYNC_PROTOCOLS",
+        use_delimiter = true
+    )]
+    protocols: Option<Vec<FeedProtocol>>,
 
     /// Target seed ratio for media media, for managers which support it ("1.0", "10", "0.1", etc)
     ///
@@ -67,7 +467,7 @@ struct Opts {
     /// Minimum time to seed a season pack, for managers which support it ("1h", "2w", etc)
     ///
     /// Defaults to `--seed-time`, if not provided.
-    #[clap(long, value_name = "DURATION", env = "SYNC_SEASON_PACK_SEED_TIME", parse(try_from_str = parse_duration::parse::parse), requires = "tv")]
+    #[clap(long, value_name = "DURATION", env = "SYNC_SEASON_PACK_SEED_TIME", parse(try_from_str = parse_duration::parse::parse))]
     season_pack_seed_time: Option<Duration>,
 
     /// target seed ratio for media from public trackers, for managers which support it ("1.0", "10", "0.1", etc)
@@ -84,8 +484,8 @@ struct Opts {
 
     /// Minimum time to seed a season pack from public trackers, for managers which support it ("1h", "2w", etc)
     ///
-    /// Defaults to `--public-seed-time`, if not provided.
-    #[clap(long, value_name = "DURATION", env = "SYNC_PUBLIC_SEASON_PACK_SEED_TIME", parse(try_from_str = parse_duration::parse::parse), requires = "tv")]
+    /// Defaults to `--public-seed-time`, then `--season-pack-seed-time`, if not provided.
+    #[clap(long, value_name = "DURATION", env = "SYNC_PUBLIC_SEASON_PACK_SEED_TIME", parse(try_from_str = parse_duration::parse::parse))]
     public_season_pack_seed_time: Option<Duration>,
 
     /// Target seed ratio for media from private trackers, for managers which support it ("1.0", "10", "0.1", etc)
@@ -102,19 +502,31 @@ struct Opts {
 
     /// Minimum time to seed a season pack from private trackers, for managers which support it ("1h", "2w", etc)
     ///
-    /// Defaults to `--private-seed-time`, if not provided.
-    #[clap(long, value_name = "DURATION", env = "SYNC_PRIVATE_SEASON_PACK_SEED_TIME", parse(try_from_str = parse_duration::parse::parse), requires = "tv")]
+    /// Defaults to `--private-seed-time`, then `--season-pack-seed-time`, if not provided.
+    #[clap(long, value_name = "DURATION", env = "SYNC_PRIVATE_SEASON_PACK_SEED_TIME", parse(try_from_str = parse_duration::parse::parse))]
     private_season_pack_seed_time: Option<Duration>,
 }
 
-#[derive(Default)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct SeedCriteria {
     seed_ratio: Option<f32>,
     seed_time: Option<Duration>,
     season_pack_seed_time: Option<Duration>,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+impl SeedCriteria {
+    fn or(self, fallback: SeedCriteria) -> SeedCriteria {
+        SeedCriteria {
+            seed_ratio: self.seed_ratio.or(fallback.seed_ratio),
+            seed_time: self.seed_time.or(fallback.seed_time),
+            season_pack_seed_time: self
+                .season_pack_seed_time
+                .or(fallback.season_pack_seed_time),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub enum IndexerPrivacy {
     Public,
@@ -122,15 +534,59 @@ pub enum IndexerPrivacy {
     SemiPrivate,
 }
 
+impl FromStr for IndexerPrivacy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "public" => Ok(Self::Public),
+            "private" => Ok(Self::Private),
+            "semi-private" => Ok(Self::SemiPrivate),
+            other => Err(format!(
+                "Unknown indexer privacy {:?} (expected public, semi-private or private)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IndexerPrivacy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Public => "public",
+            Self::Private => "private",
+            Self::SemiPrivate => "semi-private",
+        })
+    }
+}
+
 #[derive(Debug)]
 enum SourceIndexer {
-    Jackett(jackett::Indexer),
+    Jackett {
+        /// The name of the Jackett instance, unless it is the default one
+        instance: Option<String>,
+        indexer: jackett::Indexer,
+    },
 }
 
 impl SourceIndexer {
+    /// The indexer's ID within its source
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Jackett { indexer, .. } => &indexer.id,
+        }
+    }
+
     pub fn name_id(&self) -> String {
         match self {
-            Self::Jackett(ind) => format!("jackett:{}", &ind.id),
+            Self::Jackett {
+                instance: None,
+                indexer,
+            } => format!("jackett:{}", &indexer.id),
+            Self::Jackett {
+                instance: Some(instance),
+                indexer,
+            } => format!("jackett@{}:{}", instance, &indexer.id),
         }
     }
 }
@@ -165,6 +621,79 @@ struct FeedUrls {
     newznab: Option<Newznab>,
 }
 
+/// The kinds of feed an indexer may offer, and which a destination may consume
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedProtocol {
+    Newznab,
+    Torznab,
+    Rss,
+}
+
+impl FeedProtocol {
+    /// The order feeds were chosen in before the preference was configurable
+    pub const DEFAULT_PREFERENCE: [FeedProtocol; 3] = [Self::Newznab, Self::Torznab, Self::Rss];
+}
+
+impl FromStr for FeedProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "newznab" => Ok(Self::Newznab),
+            "torznab" => Ok(Self::Torznab),
+            "rss" => Ok(Self::Rss),
+            other => Err(format!(
+                "Unknown feed protocol {:?} (expected newznab, torznab or rss)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FeedProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Newznab => "newznab",
+            Self::Torznab => "torznab",
+            Self::Rss => "rss",
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Feed<'a> {
+    Newznab(&'a Newznab),
+    Torznab(&'a Torznab),
+    Rss(&'a Rss),
+}
+
+impl FeedUrls {
+    fn get(&self, protocol: FeedProtocol) -> Option<Feed<'_>> {
+        match protocol {
+            FeedProtocol::Newznab => self.newznab.as_ref().map(Feed::Newznab),
+            FeedProtocol::Torznab => self.torznab.as_ref().map(Feed::Torznab),
+            FeedProtocol::Rss => self.rss.as_ref().map(Feed::Rss),
+        }
+    }
+
+    /// The protocols for which a feed is offered
+    fn available(&self) -> Vec<FeedProtocol> {
+        FeedProtocol::DEFAULT_PREFERENCE
+            .iter()
+            .copied()
+            .filter(|&protocol| self.get(protocol).is_some())
+            .collect()
+    }
+
+    /// The first offered feed in `preference` order, if any
+    fn preferred(&self, preference: &[FeedProtocol]) -> Option<(FeedProtocol, Feed<'_>)> {
+        preference
+            .iter()
+            .find_map(|&protocol| self.get(protocol).map(|feed| (protocol, feed)))
+    }
+}
+
 #[derive(Debug)]
 pub struct Indexer {
     source: SourceIndexer,
@@ -173,8 +702,7 @@ pub struct Indexer {
     privacy: IndexerPrivacy,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut log_builder = pretty_env_logger::formatted_builder();
     match ::std::env::var("RUST_LOG") {
         Ok(s) if !s.is_empty() => {
@@ -190,103 +718,517 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     log_builder.try_init()?;
 
-    let mut opts = Opts::parse();
+    // Secrets in files are read before the options, so that they can be given in the environment,
+    // and before the runtime starts any threads, as setting environment variables isn't thread-safe
+    let config = match secret::load_env_files().and_then(|()| configure(Opts::parse())) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    opts.private_seed_time = opts.private_seed_time.or(opts.seed_time);
-    opts.public_seed_time = opts.public_seed_time.or(opts.seed_time);
-    opts.private_seed_ratio = opts.private_seed_ratio.or(opts.seed_ratio);
-    opts.public_seed_ratio = opts.public_seed_ratio.or(opts.seed_ratio);
-    opts.season_pack_seed_time = opts.season_pack_seed_time.or(opts.seed_time);
-    opts.private_season_pack_seed_time = opts
-        .private_season_pack_seed_time
-        .or(opts.private_seed_time);
-    opts.public_season_pack_seed_time = opts.public_season_pack_seed_time.or(opts.public_seed_time);
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(config))
+}
+
+async fn run(config: config::Config) -> Result<(), Box<dyn std::error::Error>> {
+    let schedule = match (config.interval, &config.schedule) {
+        (Some(interval), _) => schedule::Schedule::Interval(interval),
+        (None, Some(cron)) => schedule::Schedule::Cron(Box::new(cron.clone())),
+        (None, None) => {
+            if config.listen.is_some() {
+                log::warn!(
+                    "The control API and metrics are only served in polling mode (--interval or --schedule)"
+                );
+            }
+            let scope = schedule::SyncScope::default();
+            let run = sync(&config, &mut Sessions::default(), &scope).await?;
+            if run.failed() {
+                std::process::exit(1);
+            }
+            if config.fail_on.is_violated_by(&run.destinations) {
+                log::error!("Sync finished with failures (--fail-on {})", config.fail_on);
+                std::process::exit(2);
+            }
+            return Ok(());
+        }
+    };
 
-    let opts = opts; // drop mut marker
+    let mut scheduler = schedule::Scheduler::new(schedule)?;
+    let status = api::Status::new(config.destinations.keys());
+    if let Some(addr) = config.listen {
+        let api = api::Api::new(status.clone(), scheduler.requester(), scheduler.next_sync());
+        if let Err(e) = api::serve(addr, api) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    }
 
+    let mut sessions = Sessions::default();
+    let mut consecutive_failures = 0;
+    let mut scope = schedule::SyncScope::default();
     loop {
-        let mut indexers = vec![];
-        let mut updates = vec![];
-        let sonarr: sonarr::Sonarr;
-
-        // FETCH
-
-        if let Some(ref url) = opts.jackett {
-            log::info!("Fetching indexers from Jackett");
-            let jackett = jackett::new(url.clone()).await?;
-            let jackett_indexers = jackett.fetch_indexers().await?;
-            log::debug!("Fetched: {}", {
-                let mut i = jackett_indexers
-                    .iter()
-                    .map(|i| i.name.as_ref())
-                    .collect::<Vec<&str>>();
-                i.sort_unstable();
-                i.join(", ")
-            });
-            indexers.extend(jackett_indexers);
+        status.started(&scope);
+        let started = Instant::now();
+        let run = sync(&config, &mut sessions, &scope).await?;
+        metrics::record_run(&run, started.elapsed());
+        let failed = run.failed();
+        status.finished(run);
+
+        // Failures are logged and recorded in the run's report; we try again next time
+        if failed {
+            consecutive_failures += 1;
+            if let Some(max) = config.max_consecutive_failures {
+                if consecutive_failures >= max {
+                    log::error!(
+                        "Giving up after {} failed syncs in a row",
+                        consecutive_failures
+                    );
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            consecutive_failures = 0;
+        }
+
+        scope = match scheduler.wait().await {
+            Some(scope) => scope,
+            None => break,
+        };
+    }
+
+    log::info!("Shutting down");
+    Ok(())
+}
+
+/// Read the config file, if there is one, and override it with the options given on the command
+/// line and in the environment
+fn configure(opts: Opts) -> Result<config::Config> {
+    use config::{DestinationConfig, JackettConfig, SonarrConfig, SourceConfig};
+
+    let mut config = match opts.config {
+        Some(ref path) => config::Config::load(path)?,
+        None => config::Config::default(),
+    };
+
+    let mut named = HashSet::new();
+    for util::NamedUrl { name, url } in opts.jackett {
+        let name = name.unwrap_or_else(|| "jackett".to_owned());
+        if !named.insert(name.clone()) {
+            return Err(Error::Config(format!(
+                "more than one Jackett is named {:?} (use NAME=URL to name them)",
+                name
+            )));
+        }
+
+        match config.sources.get_mut(&name) {
+            Some(SourceConfig::Jackett(jackett)) => jackett.url = url,
+            None => {
+                let jackett = SourceConfig::Jackett(JackettConfig::new(url));
+                config.sources.insert(name, jackett);
+            }
+        }
+    }
+
+    // Source names end up in the names of indexers in destinations
+    if let Some(name) = config
+        .sources
+        .keys()
+        .find(|name| !util::is_valid_name(name))
+    {
+        return Err(Error::Config(format!(
+            "{:?} can't be used as a source name (use letters, numbers, \"-\" and \"_\")",
+            name
+        )));
+    }
+
+    let mut named = HashSet::new();
+    for util::NamedUrl { name, url } in opts.sonarr {
+        let name = name.unwrap_or_else(|| "sonarr".to_owned());
+        if !named.insert(name.clone()) {
+            return Err(Error::Config(format!(
+                "more than one Sonarr is named {:?} (use NAME=URL to name them)",
+                name
+            )));
+        }
+
+        match config.destinations.get_mut(&name) {
+            Some(DestinationConfig::Sonarr(sonarr)) => sonarr.url = url,
+            None => {
+                let sonarr = DestinationConfig::Sonarr(SonarrConfig::new(url));
+                config.destinations.insert(name, sonarr);
+            }
         }
+    }
+
+    if let Some(ref path) = opts.sonarr_config {
+        let (url, api_key) = sonarr::discover(path)?;
+        let destination = config
+            .destinations
+            .entry("sonarr".to_owned())
+            .or_insert_with(|| DestinationConfig::Sonarr(SonarrConfig::new(url)));
+        let DestinationConfig::Sonarr(sonarr) = destination;
+        sonarr.api_key = Some(api_key);
+    }
+
+    if config.sources.is_empty() || config.destinations.is_empty() {
+        return Err(Error::Config(
+            "at least one source and one destination are required".to_owned(),
+        ));
+    }
 
-        // FILTER
+    // Options given for Sonarr apply to every Sonarr destination
+    for destination in config.destinations.values_mut() {
+        let DestinationConfig::Sonarr(sonarr) = destination;
+        if let Some(ref protocols) = opts.sonarr_protocols {
+            sonarr.protocols = protocols.clone();
+        }
+        if let Some(policy) = opts.sonarr_test_failure {
+            sonarr.test_failure = policy;
+        }
+        sonarr.force_save |= opts.sonarr_force_save;
+        sonarr.prune |= opts.sonarr_prune;
+        if let Some(concurrency) = opts.sonarr_concurrency {
+            sonarr.concurrency = concurrency;
+        }
+        if opts.sonarr_categories.is_some() {
+            sonarr.categories = opts.sonarr_categories.clone();
+        }
+        if opts.sonarr_anime_categories.is_some() {
+            sonarr.anime_categories = opts.sonarr_anime_categories.clone();
+        }
+        if opts.sonarr_api_key.is_some() {
+            sonarr.api_key = opts.sonarr_api_key.clone();
+        }
+        if !opts.sonarr_rewrite.is_empty() {
+            sonarr.rewrite = opts.sonarr_rewrite.clone();
+        }
+        if !opts.sonarr_ca_cert.is_empty() {
+            sonarr.tls.ca_certs = opts.sonarr_ca_cert.clone();
+        }
+        if opts.sonarr_client_cert.is_some() {
+            sonarr.tls.client_cert = opts.sonarr_client_cert.clone();
+            sonarr.tls.client_key = opts.sonarr_client_key.clone();
+        }
+        sonarr.tls.insecure |= opts.sonarr_insecure;
+        if opts.sonarr_proxy.is_some() {
+            sonarr.proxy = opts.sonarr_proxy.clone();
+        }
+        for header in &opts.sonarr_header {
+            sonarr.headers.insert(header.clone());
+        }
+        if opts.sonarr_basic_auth.is_some() {
+            sonarr.basic_auth = opts.sonarr_basic_auth.clone();
+        }
+    }
+    for util::NamedUrl { name, url } in opts.jackett_feed_url {
+        let name = name.unwrap_or_else(|| "jackett".to_owned());
+        match config.sources.get_mut(&name) {
+            Some(SourceConfig::Jackett(jackett)) => jackett.feed_url = Some(url),
+            None => {
+                return Err(Error::Config(format!(
+                    "there is no Jackett named {:?} to give a feed URL for",
+                    name
+                )))
+            }
+        }
+    }
+    // Options given for Jackett apply to every Jackett source
+    for source in config.sources.values_mut() {
+        let SourceConfig::Jackett(jackett) = source;
+        if opts.jackett_password.is_some() {
+            jackett.password = opts.jackett_password.clone();
+        }
+        if !opts.jackett_ca_cert.is_empty() {
+            jackett.tls.ca_certs = opts.jackett_ca_cert.clone();
+        }
+        if opts.jackett_client_cert.is_some() {
+            jackett.tls.client_cert = opts.jackett_client_cert.clone();
+            jackett.tls.client_key = opts.jackett_client_key.clone();
+        }
+        jackett.tls.insecure |= opts.jackett_insecure;
+        if opts.jackett_proxy.is_some() {
+            jackett.proxy = opts.jackett_proxy.clone();
+        }
+        for header in &opts.jackett_header {
+            jackett.headers.insert(header.clone());
+        }
+        if opts.jackett_basic_auth.is_some() {
+            jackett.basic_auth = opts.jackett_basic_auth.clone();
+        }
+    }
 
-        if !opts.indexers_to_sync.is_empty() {
-            let filters: Vec<_> = opts
-                .indexers_to_sync
+    // The secret command is asked before falling back to credentials in URLs
+    if let Some(command) = opts.secret_command.map(secret::SecretCommand) {
+        for (name, source) in &mut config.sources {
+            let SourceConfig::Jackett(jackett) = source;
+            if jackett.password.is_none() {
+                jackett.password = command.fetch(&format!("{}/password", name))?;
+            }
+        }
+        for (name, destination) in &mut config.destinations {
+            let DestinationConfig::Sonarr(sonarr) = destination;
+            if sonarr.api_key.is_none() {
+                sonarr.api_key = command.fetch(&format!("{}/api-key", name))?;
+            }
+        }
+    }
+
+    // Either way of polling replaces the other from the file
+    if opts.interval.is_some() || opts.schedule.is_some() {
+        config.interval = opts.interval;
+        config.schedule = opts.schedule;
+    }
+    config.max_consecutive_failures = opts
+        .max_consecutive_failures
+        .or(config.max_consecutive_failures);
+    config.listen = opts.listen.or(config.listen);
+    config.fail_on = opts.fail_on.unwrap_or(config.fail_on);
+    config.output = opts.output.unwrap_or(config.output);
+    config.retry.retries = opts.retries.unwrap_or(config.retry.retries);
+    config.retry.delay = opts.retry_delay.unwrap_or(config.retry.delay);
+    config.retry.max_delay = opts.retry_max_delay.unwrap_or(config.retry.max_delay);
+    config.retry.timeout = opts.timeout.unwrap_or(config.retry.timeout);
+    if !opts.indexers_to_sync.is_empty() {
+        config.filter.include = opts.indexers_to_sync;
+    }
+    if !opts.exclude.is_empty() {
+        config.filter.exclude = opts.exclude;
+    }
+    if let Some(privacy) = opts.privacy {
+        config.filter.privacy = privacy;
+    }
+    if let Some(protocols) = opts.protocols {
+        config.filter.protocols = protocols;
+    }
+
+    let seed = &mut config.seed;
+    seed.ratio = opts.seed_ratio.or(seed.ratio);
+    seed.time = opts.seed_time.or(seed.time);
+    seed.season_pack_time = opts.season_pack_seed_time.or(seed.season_pack_time);
+    seed.public.ratio = opts.public_seed_ratio.or(seed.public.ratio);
+    seed.public.time = opts.public_seed_time.or(seed.public.time);
+    seed.public.season_pack_time = opts
+        .public_season_pack_seed_time
+        .or(seed.public.season_pack_time);
+    seed.private.ratio = opts.private_seed_ratio.or(seed.private.ratio);
+    seed.private.time = opts.private_seed_time.or(seed.private.time);
+    seed.private.season_pack_time = opts
+        .private_season_pack_seed_time
+        .or(seed.private.season_pack_time);
+
+    let insecure = config
+        .sources
+        .iter()
+        .filter(|(_, config::SourceConfig::Jackett(jackett))| jackett.tls.insecure)
+        .map(|(name, _)| name)
+        .chain(
+            config
+                .destinations
                 .iter()
-                .map(|f| f.to_lowercase())
-                .collect();
+                .filter(|(_, config::DestinationConfig::Sonarr(sonarr))| sonarr.tls.insecure)
+                .map(|(name, _)| name),
+        );
+    for name in insecure {
+        log::warn!("TLS certificates of {} will not be verified", name);
+    }
 
-            indexers = indexers
-                .into_iter()
-                .filter(|i| filters.iter().any(|f| i.name.to_lowercase().contains(f)))
-                .collect();
+    connect_sockets(&mut config)?;
+    Ok(config)
+}
+
+/// Reach sources and destinations given as `unix://` URLs through their sockets
+fn connect_sockets(config: &mut config::Config) -> Result<()> {
+    for (name, source) in &mut config.sources {
+        let config::SourceConfig::Jackett(jackett) = source;
+        if let Some(socket) = socket::UnixSocket::from_url(&jackett.url) {
+            let socket = socket.map_err(Error::Config)?;
+            // Destinations can't reach the socket through us
+            if jackett.feed_url.is_none() {
+                return Err(Error::Config(format!(
+                    "{} is a Unix socket, so a feed URL is needed for destinations to reach it",
+                    name
+                )));
+            }
+            jackett.url = socket.base_url()?;
+            jackett.socket = Some(socket);
+        }
+    }
 
-            log::debug!(
-                "Filtered indexers to {}",
-                if indexers.is_empty() {
-                    "empty list".to_owned()
-                } else {
-                    indexers
+    for destination in config.destinations.values_mut() {
+        let config::DestinationConfig::Sonarr(sonarr) = destination;
+        if let Some(socket) = socket::UnixSocket::from_url(&sonarr.url) {
+            let socket = socket.map_err(Error::Config)?;
+            sonarr.url = socket.base_url()?;
+            sonarr.socket = Some(socket);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sources which are kept logged in between syncs in polling mode, by name
+#[derive(Default)]
+struct Sessions {
+    jackett: HashMap<String, jackett::Jackett>,
+}
+
+async fn fetch_jackett(
+    name: &str,
+    config: &config::JackettConfig,
+    retry: http::RetryPolicy,
+    sessions: &mut Sessions,
+) -> Result<Vec<Indexer>> {
+    let jackett = match sessions.jackett.remove(name) {
+        Some(jackett) => jackett,
+        None => jackett::new(
+            name,
+            config.url.clone(),
+            config.password.clone(),
+            retry,
+            config.client()?,
+        )
+        .await?
+        .feed_url(config.feed_url.clone()),
+    };
+
+    let indexers = jackett.fetch_indexers().await?;
+    // Only keep sessions which work, so that we start afresh after anything goes wrong
+    sessions.jackett.insert(name.to_owned(), jackett);
+    Ok(indexers)
+}
+
+async fn sync(
+    config: &config::Config,
+    sessions: &mut Sessions,
+    scope: &schedule::SyncScope,
+) -> Result<report::RunReport, Box<dyn std::error::Error>> {
+    let mut indexers = vec![];
+    let mut updates = vec![];
+    let mut run = report::RunReport::default();
+
+    // FETCH
+
+    for (name, source) in &config.sources {
+        let config::SourceConfig::Jackett(jackett) = source;
+        log::info!("Fetching indexers from {}", name);
+        match fetch_jackett(name, jackett, config.retry, sessions).await {
+            Ok(jackett_indexers) => {
+                log::debug!("Fetched: {}", {
+                    let mut i = jackett_indexers
                         .iter()
                         .map(|i| i.name.as_ref())
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                }
-            );
+                        .collect::<Vec<&str>>();
+                    i.sort_unstable();
+                    i.join(", ")
+                });
+                indexers.extend(jackett_indexers);
+            }
+            Err(e) => run.error(format!("Unable to fetch indexers from {}: {}", name, e)),
         }
+    }
+
+    // Syncing a partial list of indexers could prune the missing ones, so don't sync at all
+    if run.failed() {
+        config.output.write(&run)?;
+        return Ok(run);
+    }
 
-        // UPDATE
+    // FILTER
 
-        if indexers.is_empty() {
-            log::warn!("No indexers to sync");
-        } else {
-            if let Some(ref url) = opts.sonarr {
-                log::info!("Updating indexers in Sonarr");
-                sonarr = sonarr::new(url.clone())?
-                    .private_seed_criteria(SeedCriteria {
-                        seed_time: opts.private_seed_time,
-                        seed_ratio: opts.private_seed_ratio,
-                        season_pack_seed_time: opts.private_season_pack_seed_time,
-                    })
-                    .public_seed_criteria(SeedCriteria {
-                        seed_time: opts.public_seed_time,
-                        seed_ratio: opts.public_seed_ratio,
-                        season_pack_seed_time: opts.public_season_pack_seed_time,
-                    });
-                updates.push(sonarr.update_indexers(&indexers));
+    indexers.retain(|i| {
+        let selected = config.filter.is_selected(i);
+        run.indexers.push(report::SourceIndexerReport::new(
+            i,
+            selected,
+            config.filter.included_by(i).map(ToString::to_string),
+        ));
+        selected
+    });
+
+    // A sync of only some indexers leaves the others out without unselecting them in the report
+    if let Some(ref pattern) = scope.indexer {
+        indexers.retain(|i| pattern.matches(i));
+    }
+
+    if !config.filter.is_empty() || scope.indexer.is_some() {
+        log::debug!(
+            "Filtered indexers to {}",
+            if indexers.is_empty() {
+                "empty list".to_owned()
+            } else {
+                indexers
+                    .iter()
+                    .map(|i| i.name.as_ref())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+        );
+    }
+
+    // UPDATE
+
+    if indexers.is_empty() {
+        log::warn!("No indexers to sync");
+    } else {
+        for (name, destination) in &config.destinations {
+            if matches!(&scope.destination, Some(only) if only != name) {
+                continue;
+            }
+            let config::DestinationConfig::Sonarr(sonarr) = destination;
+            log::info!("Updating indexers in {}", name);
+
+            // Each destination may narrow the indexers down further
+            let selected: Vec<_> = indexers
+                .iter()
+                .filter(|i| sonarr.filter.is_selected(i))
+                .collect();
+            let (public_seed_criteria, private_seed_criteria) =
+                sonarr.seed.criteria_or(&config.seed);
+
+            let client = sonarr
+                .client()
+                .and_then(|client| sonarr::new(sonarr.url.clone(), sonarr.api_key.clone(), client));
+            match client {
+                Ok(client) => updates.push(async move {
+                    client
+                        .name(name)
+                        .protocol_preference(sonarr.protocols.clone())
+                        .test_failure_policy(sonarr.test_failure)
+                        .force_save(sonarr.force_save)
+                        // Indexers left out of a sync of a single indexer aren't gone
+                        .prune(sonarr.prune && scope.indexer.is_none())
+                        .concurrency(sonarr.concurrency)
+                        .categories(sonarr.categories.clone())
+                        .anime_categories(sonarr.anime_categories.clone())
+                        .url_rewrites(sonarr.rewrite.clone())
+                        .retry_policy(config.retry)
+                        .private_seed_criteria(private_seed_criteria)
+                        .public_seed_criteria(public_seed_criteria)
+                        .update_indexers(selected)
+                        .await
+                        .map_err(|e| (name, e))
+                }),
+                Err(e) => run.destination_error(name, e.to_string()),
             }
+        }
 
-            for future in updates {
-                future.await?;
+        // Every destination is updated at once
+        for result in futures::future::join_all(updates).await {
+            match result {
+                Ok(report) => run.destinations.push(report),
+                Err((name, e)) => run.destination_error(name, e.to_string()),
             }
         }
 
-        if let Some(interval) = opts.interval {
-            log::info!("Sleeping for {} seconds", interval.as_secs_f64());
-            std::thread::sleep(interval);
-        } else {
-            break;
+        for report in &run.destinations {
+            report.log_summary();
         }
     }
 
-    Ok(())
+    config.output.write(&run)?;
+    Ok(run)
 }
@@ -0,0 +1,185 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge, register_histogram, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge_vec, Encoder, Gauge, Histogram, HistogramVec, IntCounterVec, IntGaugeVec,
+    TextEncoder,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::http::Upstream;
+use crate::report::RunReport;
+use crate::IndexerPrivacy;
+use crate::{Error, Result};
+
+lazy_static! {
+    static ref RUNS: IntCounterVec = register_int_counter_vec!(
+        "indexer_sync_runs_total",
+        "Syncs run, by whether they succeeded",
+        &["result"]
+    )
+    .expect("metric is registered once");
+    static ref RUN_DURATION: Histogram = register_histogram!(
+        "indexer_sync_run_duration_seconds",
+        "How long each sync took",
+        vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0]
+    )
+    .expect("metric is registered once");
+    static ref LAST_SUCCESS: Gauge = register_gauge!(
+        "indexer_sync_last_success_timestamp_seconds",
+        "When the last successful sync finished, as a Unix timestamp"
+    )
+    .expect("metric is registered once");
+    static ref DESTINATION_INDEXERS: IntCounterVec = register_int_counter_vec!(
+        "indexer_sync_destination_indexers_total",
+        "Indexers synced to each destination, by what was done with them",
+        &["destination", "outcome"]
+    )
+    .expect("metric is registered once");
+    static ref SOURCE_INDEXERS: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_sync_source_indexers",
+        "Indexers found in every source in the last sync, by privacy",
+        &["privacy"]
+    )
+    .expect("metric is registered once");
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "indexer_sync_http_requests_total",
+        "Requests sent to sources and destinations, by response status (or \"error\")",
+        &["service", "name", "status"]
+    )
+    .expect("metric is registered once");
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "indexer_sync_http_request_duration_seconds",
+        "How long requests to sources and destinations took",
+        &["service", "name"]
+    )
+    .expect("metric is registered once");
+}
+
+/// The outcomes every destination counts, so that they're reported before first happening
+const OUTCOMES: [&str; 6] = [
+    "created",
+    "updated",
+    "unchanged",
+    "skipped",
+    "failed",
+    "pruned",
+];
+
+/// Record a sync once it has finished
+pub fn record_run(run: &RunReport, duration: Duration) {
+    RUN_DURATION.observe(duration.as_secs_f64());
+    if run.failed() {
+        RUNS.with_label_values(&["failure"]).inc();
+    } else {
+        RUNS.with_label_values(&["success"]).inc();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        LAST_SUCCESS.set(now.as_secs_f64());
+    }
+
+    // A sync which couldn't fetch indexers leaves the last known counts
+    if !run.indexers.is_empty() {
+        let privacies = [
+            IndexerPrivacy::Public,
+            IndexerPrivacy::SemiPrivate,
+            IndexerPrivacy::Private,
+        ];
+        for privacy in &privacies {
+            let count = run
+                .indexers
+                .iter()
+                .filter(|i| i.privacy == *privacy)
+                .count();
+            SOURCE_INDEXERS
+                .with_label_values(&[&privacy.to_string()])
+                .set(count as i64);
+        }
+    }
+
+    for report in &run.destinations {
+        for outcome in &OUTCOMES {
+            let count = report.with_label(outcome).count();
+            DESTINATION_INDEXERS
+                .with_label_values(&[&report.destination, outcome])
+                .inc_by(count as u64);
+        }
+    }
+}
+
+/// Record a single attempt at a request
+pub fn record_request(
+    upstream: Upstream<'_>,
+    result: &Result<reqwest::Response>,
+    duration: Duration,
+) {
+    let status = match result {
+        Ok(response) => response.status().as_u16().to_string(),
+        Err(Error::Http(e)) => match e.status() {
+            Some(status) => status.as_u16().to_string(),
+            None => "error".to_owned(),
+        },
+        Err(_) => "error".to_owned(),
+    };
+    HTTP_REQUESTS
+        .with_label_values(&[upstream.service, upstream.name, &status])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[upstream.service, upstream.name])
+        .observe(duration.as_secs_f64());
+}
+
+/// Every metric, in Prometheus' text format
+pub fn render() -> (String, Vec<u8>) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("Unable to encode metrics: {}", e);
+    }
+    (encoder.format_type().to_owned(), buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{DestinationReport, IndexerReport, Outcome, SourceIndexerReport};
+
+    #[test]
+    fn test_record_run() {
+        let mut sonarr = DestinationReport::new("metrics-test");
+        sonarr.record(IndexerReport::new("jackett:rarbg", "RARBG"));
+        let mut failed = IndexerReport::new("jackett:eztv", "EZTV");
+        failed.outcome = Outcome::Failed("rejected".to_owned());
+        sonarr.record(failed);
+
+        let run = RunReport {
+            indexers: vec![SourceIndexerReport {
+                id: "jackett:rarbg".to_owned(),
+                name: "RARBG".to_owned(),
+                privacy: IndexerPrivacy::SemiPrivate,
+                protocols: vec![],
+                selected: true,
+                matched: None,
+            }],
+            destinations: vec![sonarr],
+            ..RunReport::default()
+        };
+        record_run(&run, Duration::from_secs(3));
+        record_run(&run, Duration::from_secs(3));
+
+        let (content_type, metrics) = render();
+        let metrics = String::from_utf8(metrics).unwrap();
+        assert!(content_type.starts_with("text/plain"));
+        assert!(metrics.contains(
+            r#"indexer_sync_destination_indexers_total{destination="metrics-test",outcome="unchanged"} 2"#
+        ));
+        assert!(metrics.contains(
+            r#"indexer_sync_destination_indexers_total{destination="metrics-test",outcome="failed"} 2"#
+        ));
+        assert!(metrics.contains(
+            r#"indexer_sync_destination_indexers_total{destination="metrics-test",outcome="pruned"} 0"#
+        ));
+        assert!(metrics.contains(r#"indexer_sync_source_indexers{privacy="semi-private"} 1"#));
+        assert!(metrics.contains("indexer_sync_last_success_timestamp_seconds "));
+    }
+}
@@ -0,0 +1,386 @@
+use crate::{FeedProtocol, Indexer, IndexerPrivacy};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Everything that happened in one sync, for machine-readable output
+#[derive(Debug, Default, Serialize)]
+pub struct RunReport {
+    pub indexers: Vec<SourceIndexerReport>,
+    pub destinations: Vec<DestinationReport>,
+    /// Problems which stopped a source or destination from being synced at all
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+    /// Why each destination which couldn't be updated at all wasn't, by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub destination_errors: BTreeMap<String, String>,
+}
+
+impl RunReport {
+    pub fn error(&mut self, message: String) {
+        log::error!("{}", &message);
+        self.errors.push(message);
+    }
+
+    /// Record that a destination couldn't be updated at all
+    pub fn destination_error(&mut self, destination: &str, error: String) {
+        self.error(format!("Unable to update {}: {}", destination, error));
+        self.destination_errors
+            .insert(destination.to_owned(), error);
+    }
+
+    pub fn failed(&self) -> bool {
+        !self.errors.is_empty()
+    }
+}
+
+/// An indexer discovered in a source, and whether it was selected for syncing
+#[derive(Clone, Debug, Serialize)]
+pub struct SourceIndexerReport {
+    pub id: String,
+    pub name: String,
+    pub privacy: IndexerPrivacy,
+    pub protocols: Vec<FeedProtocol>,
+    pub selected: bool,
+    /// The INDEXERS term (or included pattern in the config file) which selected it, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched: Option<String>,
+}
+
+impl SourceIndexerReport {
+    pub fn new(indexer: &Indexer, selected: bool, matched: Option<String>) -> Self {
+        Self {
+            id: indexer.source.name_id(),
+            name: indexer.name.clone(),
+            privacy: indexer.privacy,
+            protocols: indexer.urls.available(),
+            selected,
+            matched,
+        }
+    }
+}
+
+/// What happened to a single indexer in a destination
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "outcome", content = "reason", rename_all = "snake_case")]
+pub enum Outcome {
+    Created,
+    Updated,
+    Unchanged,
+    Skipped(String),
+    Failed(String),
+    Pruned,
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Updated => "updated",
+            Self::Unchanged => "unchanged",
+            Self::Skipped(_) => "skipped",
+            Self::Failed(_) => "failed",
+            Self::Pruned => "pruned",
+        }
+    }
+
+    fn reason(&self) -> Option<&str> {
+        match self {
+            Self::Skipped(reason) | Self::Failed(reason) => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct IndexerReport {
+    /// The indexer's ID in its source (`jackett:rarbg`, etc)
+    pub id: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<FeedProtocol>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub anime_categories: Vec<usize>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl IndexerReport {
+    pub fn new(id: impl Into<String>, name: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            name: name.into(),
+            protocol: None,
+            categories: vec![],
+            anime_categories: vec![],
+            outcome: Outcome::Unchanged,
+        }
+    }
+}
+
+/// The outcome of syncing every indexer to one destination
+#[derive(Debug, Serialize)]
+pub struct DestinationReport {
+    pub destination: String,
+    pub indexers: Vec<IndexerReport>,
+}
+
+impl DestinationReport {
+    pub fn new(destination: impl Into<String>) -> Self {
+        Self {
+            destination: destination.into(),
+            indexers: vec![],
+        }
+    }
+
+    pub fn record(&mut self, indexer: IndexerReport) {
+        self.indexers.push(indexer);
+    }
+
+    /// The indexers with an outcome ("created", "failed", etc)
+    pub fn with_label<'a>(&'a self, label: &'a str) -> impl Iterator<Item = &'a IndexerReport> {
+        self.indexers
+            .iter()
+            .filter(move |i| i.outcome.label() == label)
+    }
+
+    pub fn failures(&self) -> usize {
+        self.with_label("failed").count()
+    }
+
+    /// Whether there were failures and nothing at all was synced successfully
+    pub fn failed_entirely(&self) -> bool {
+        self.failures() > 0
+            && !self.indexers.iter().any(|i| {
+                matches!(
+                    i.outcome,
+                    Outcome::Created | Outcome::Updated | Outcome::Unchanged
+                )
+            })
+    }
+
+    pub fn log_summary(&self) {
+        let labels = [
+            "created",
+            "updated",
+            "unchanged",
+            "skipped",
+            "failed",
+            "pruned",
+        ];
+
+        log::info!(
+            "{}: {}",
+            &self.destination,
+            labels
+                .iter()
+                .map(|label| format!("{} {}", self.with_label(label).count(), label))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        for label in labels.iter() {
+            let indexers: Vec<_> = self
+                .with_label(label)
+                .map(|i| match i.outcome.reason() {
+                    Some(reason) => format!("{} ({})", i.name, reason),
+                    None => i.name.clone(),
+                })
+                .collect();
+
+            if indexers.is_empty() {
+                continue;
+            }
+
+            let line = format!("    {}: {}", label, indexers.join(", "));
+            match *label {
+                "failed" => log::error!("{}", line),
+                "skipped" => log::warn!("{}", line),
+                "unchanged" => log::debug!("{}", line),
+                _ => log::info!("{}", line),
+            }
+        }
+    }
+}
+
+/// How the outcome of each sync is written to stdout
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Nothing beyond the log output
+    #[default]
+    Text,
+    /// One JSON document per line
+    Json,
+    /// YAML documents separated by `---`
+    Yaml,
+}
+
+impl OutputFormat {
+    pub fn write(&self, report: &RunReport) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Self::Text => {}
+            Self::Json => println!("{}", serde_json::to_string(report)?),
+            Self::Yaml => println!("{}", serde_yaml::to_string(report)?),
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "yaml" => Ok(Self::Yaml),
+            other => Err(format!(
+                "Unknown output format {:?} (expected text, json or yaml)",
+                other
+            )),
+        }
+    }
+}
+
+/// When a completed sync should still exit with a failure status
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Always exit successfully once the sync has run
+    Never,
+    /// Fail if any indexer failed to sync to any destination
+    #[default]
+    Any,
+    /// Fail only if some destination had failures and nothing synced successfully
+    All,
+}
+
+impl FailurePolicy {
+    pub fn is_violated_by(&self, reports: &[DestinationReport]) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Any => reports.iter().any(|r| r.failures() > 0),
+            Self::All => reports.iter().any(DestinationReport::failed_entirely),
+        }
+    }
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(Self::Never),
+            "any" => Ok(Self::Any),
+            "all" => Ok(Self::All),
+            other => Err(format!(
+                "Unknown failure policy {:?} (expected never, any or all)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FailurePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Never => "never",
+            Self::Any => "any",
+            Self::All => "all",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(outcomes: Vec<Outcome>) -> DestinationReport {
+        let mut report = DestinationReport::new("Sonarr");
+        for (i, outcome) in outcomes.into_iter().enumerate() {
+            let mut indexer =
+                IndexerReport::new(format!("jackett:{}", i), format!("Indexer {}", i));
+            indexer.outcome = outcome;
+            report.record(indexer);
+        }
+        report
+    }
+
+    #[test]
+    fn test_partial_failure() {
+        let reports = [report(vec![
+            Outcome::Updated,
+            Outcome::Failed("rejected".to_owned()),
+        ])];
+        assert!(!FailurePolicy::Never.is_violated_by(&reports));
+        assert!(FailurePolicy::Any.is_violated_by(&reports));
+        assert!(!FailurePolicy::All.is_violated_by(&reports));
+    }
+
+    #[test]
+    fn test_total_failure() {
+        let reports = [report(vec![
+            Outcome::Skipped("no usable feed".to_owned()),
+            Outcome::Failed("rejected".to_owned()),
+        ])];
+        assert!(!FailurePolicy::Never.is_violated_by(&reports));
+        assert!(FailurePolicy::Any.is_violated_by(&reports));
+        assert!(FailurePolicy::All.is_violated_by(&reports));
+    }
+
+    #[test]
+    fn test_serialize_outcome() -> serde_json::Result<()> {
+        let mut indexer = IndexerReport::new("jackett:rarbg", "RARBG");
+        indexer.protocol = Some(FeedProtocol::Torznab);
+        indexer.categories = vec![5000];
+        indexer.outcome = Outcome::Failed("rejected".to_owned());
+        assert_eq!(
+            serde_json::to_string(&indexer)?,
+            r#"{"id":"jackett:rarbg","name":"RARBG","protocol":"torznab","categories":[5000],"outcome":"failed","reason":"rejected"}"#
+        );
+
+        indexer.outcome = Outcome::Created;
+        assert_eq!(
+            serde_json::to_string(&indexer)?,
+            r#"{"id":"jackett:rarbg","name":"RARBG","protocol":"torznab","categories":[5000],"outcome":"created"}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_errors() -> serde_json::Result<()> {
+        let mut run = RunReport::default();
+        assert!(!run.failed());
+        assert_eq!(
+            serde_json::to_string(&run)?,
+            r#"{"indexers":[],"destinations":[]}"#
+        );
+
+        run.error("Unable to fetch indexers from Jackett".to_owned());
+        assert!(run.failed());
+        assert_eq!(
+            serde_json::to_string(&run)?,
+            r#"{"indexers":[],"destinations":[],"errors":["Unable to fetch indexers from Jackett"]}"#
+        );
+
+        let mut run = RunReport::default();
+        run.destination_error("sonarr", "HTTP error: timed out".to_owned());
+        assert!(run.failed());
+        assert_eq!(
+            serde_json::to_string(&run)?,
+            r#"{"indexers":[],"destinations":[],"errors":["Unable to update sonarr: HTTP error: timed out"],"destination_errors":{"sonarr":"HTTP error: timed out"}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_failures() {
+        let reports = [report(vec![Outcome::Created, Outcome::Pruned])];
+        assert!(!FailurePolicy::Any.is_violated_by(&reports));
+        assert!(!FailurePolicy::All.is_violated_by(&reports));
+    }
+}
@@ -0,0 +1,193 @@
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+use crate::filter::Pattern;
+
+/// How many syncs requested through the control API can wait for their turn at once
+const PENDING_API_REQUESTS: usize = 8;
+
+/// When polling mode should run each sync
+#[derive(Debug)]
+pub enum Schedule {
+    Interval(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// When the next sync is due, if it is ever due again
+    fn next_time(&self) -> Option<DateTime<Local>> {
+        match self {
+            Self::Interval(interval) => chrono::Duration::from_std(*interval)
+                .ok()
+                .and_then(|interval| Local::now().checked_add_signed(interval)),
+            Self::Cron(schedule) => schedule.upcoming(Local).next(),
+        }
+    }
+}
+
+/// What a sync covers. Scheduled syncs cover everything, but the control API can ask for a sync of
+/// a single indexer or destination.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SyncScope {
+    /// Only sync indexers matching this, without pruning any others
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub indexer: Option<Pattern>,
+    /// Only sync to the destination with this name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+}
+
+/// Parse a cron expression, accepting the usual 5-field form as well as the 6- and 7-field forms
+/// (with seconds, and optionally years) understood by the `cron` crate.
+pub fn parse_cron(expr: &str) -> Result<cron::Schedule, String> {
+    let expr = expr.trim();
+    let expr = if expr.split_whitespace().count() == 5 {
+        format!("0 {}", expr)
+    } else {
+        expr.to_owned()
+    };
+
+    cron::Schedule::from_str(&expr).map_err(|e| format!("Invalid cron expression: {}", e))
+}
+
+/// Waits for syncs to become due in polling mode, while listening for signals asking for an
+/// immediate sync (SIGUSR1) or for shutdown (SIGTERM and SIGINT), and for syncs requested through
+/// the control API.
+pub struct Scheduler {
+    schedule: Schedule,
+    shutdown: watch::Receiver<bool>,
+    sync_requests: SyncRequests,
+    api_requests: mpsc::Receiver<SyncScope>,
+    api_requester: mpsc::Sender<SyncScope>,
+    next_sync: watch::Sender<Option<DateTime<Local>>>,
+    next_sync_receiver: watch::Receiver<Option<DateTime<Local>>>,
+}
+
+impl Scheduler {
+    pub fn new(schedule: Schedule) -> std::io::Result<Self> {
+        let (shutdown_tx, shutdown) = watch::channel(false);
+
+        // Signals are only handled here, so that they keep their default behaviour outside of
+        // polling mode.
+        let sync_requests = SyncRequests::new()?;
+        #[cfg(unix)]
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = terminate.recv() => {},
+                _ = tokio::signal::ctrl_c() => {},
+            }
+            #[cfg(not(unix))]
+            let _ = tokio::signal::ctrl_c().await;
+
+            log::info!("Shutting down once any sync in progress has finished");
+            let _ = shutdown_tx.send(true);
+        });
+
+        let (api_requester, api_requests) = mpsc::channel(PENDING_API_REQUESTS);
+        let (next_sync, next_sync_receiver) = watch::channel(None);
+
+        Ok(Self {
+            schedule,
+            shutdown,
+            sync_requests,
+            api_requests,
+            api_requester,
+            next_sync,
+            next_sync_receiver,
+        })
+    }
+
+    /// Somewhere to send requests for a sync, which are handled one at a time between syncs. Only a
+    /// few can be waiting at once.
+    pub fn requester(&self) -> mpsc::Sender<SyncScope> {
+        self.api_requester.clone()
+    }
+
+    /// When the next scheduled sync is due, which is `None` while a sync is running
+    pub fn next_sync(&self) -> watch::Receiver<Option<DateTime<Local>>> {
+        self.next_sync_receiver.clone()
+    }
+
+    /// Wait until the next sync should run, and return what it should cover. Returns `None` if we
+    /// should shut down instead.
+    pub async fn wait(&mut self) -> Option<SyncScope> {
+        if *self.shutdown.borrow() {
+            return None;
+        }
+
+        let next = match self.schedule.next_time() {
+            Some(next) => next,
+            None => {
+                log::warn!("The cron schedule has no more upcoming times");
+                return None;
+            }
+        };
+        let delay = (next - Local::now()).to_std().unwrap_or_default();
+        log::info!("Next sync in {} seconds", delay.as_secs_f64().round());
+        let _ = self.next_sync.send(Some(next));
+
+        let scope = tokio::select! {
+            _ = tokio::time::sleep(delay) => Some(SyncScope::default()),
+            _ = self.shutdown.changed() => None,
+            _ = self.sync_requests.recv() => {
+                log::info!("Sync requested");
+                Some(SyncScope::default())
+            }
+            Some(scope) = self.api_requests.recv() => {
+                log::info!("Sync requested through the control API");
+                Some(scope)
+            }
+        };
+        let _ = self.next_sync.send(None);
+        scope
+    }
+}
+
+/// Requests for an immediate sync, which are made with SIGUSR1 where it exists
+#[cfg(unix)]
+struct SyncRequests(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl SyncRequests {
+    fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self(signal(SignalKind::user_defined1())?))
+    }
+
+    async fn recv(&mut self) {
+        self.0.recv().await;
+    }
+}
+
+#[cfg(not(unix))]
+struct SyncRequests;
+
+#[cfg(not(unix))]
+impl SyncRequests {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) {
+        futures::future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cron() {
+        assert!(parse_cron("0 */6 * * *").is_ok());
+        assert!(parse_cron("30 0 */6 * * *").is_ok());
+        assert!(parse_cron("every six hours").is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use serde::{de, Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{Error, Result};
+
+/// A credential in the config file, given either as it is or as somewhere to find it:
+///
+/// ```toml
+/// api-key = "abc123"
+/// api-key = { file = "/run/secrets/sonarr-api-key" }
+/// api-key = { command = "pass show sonarr" }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Secret {
+    Value(String),
+    File { file: PathBuf },
+    Command { command: String },
+}
+
+impl Secret {
+    fn reveal(self) -> Result<String> {
+        match self {
+            Self::Value(value) => Ok(value),
+            Self::File { file } => read_file(&file),
+            Self::Command { command } => run(&command, None),
+        }
+    }
+}
+
+/// Read a secret in the config file as soon as the file is loaded
+pub fn optional<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Secret::deserialize(deserializer)?
+        .reveal()
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// A command which is run to fetch any credentials which aren't given elsewhere
+#[derive(Clone, Debug)]
+pub struct SecretCommand(pub String);
+
+impl SecretCommand {
+    /// Fetch the secret with the given name ("sonarr/api-key", etc). Returns `None` if the
+    /// command prints nothing, as there is no such secret.
+    pub fn fetch(&self, name: &str) -> Result<Option<String>> {
+        log::debug!("Fetching {} with the secret command", name);
+        run(&self.0, Some(name)).map(|secret| Some(secret).filter(|s| !s.is_empty()))
+    }
+}
+
+/// Run a command with the shell, passing it the name of the secret (if any) as its first argument
+fn run(command: &str, name: Option<&str>) -> Result<String> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command).arg("sh");
+        shell
+    };
+    if let Some(name) = name {
+        shell.arg(name).env("SYNC_SECRET_NAME", name);
+    }
+
+    let failed = |reason: String| {
+        Error::Config(format!(
+            "secret command {:?} failed{}: {}",
+            command,
+            name.map(|n| format!(" for {}", n)).unwrap_or_default(),
+            reason
+        ))
+    };
+
+    let output = shell.output().map_err(|e| failed(e.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(failed(format!("{} ({})", output.status, stderr.trim())));
+    }
+
+    String::from_utf8(output.stdout)
+        .map(|secret| trim_line_ending(&secret).to_owned())
+        .map_err(|_| failed("output is not UTF-8".to_owned()))
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path)
+        .map(|secret| trim_line_ending(&secret).to_owned())
+        .map_err(|e| Error::Config(format!("unable to read {}: {}", path.display(), e)))
+}
+
+/// Files and command output usually end with a newline which isn't part of the secret
+fn trim_line_ending(s: &str) -> &str {
+    s.strip_suffix('\n')
+        .map(|s| s.strip_suffix('\r').unwrap_or(s))
+        .unwrap_or(s)
+}
+
+/// The environment variables which can carry credentials, and so can be read from a file. Others
+/// aren't secret, and some such as `SYNC_CONFIG` already name a file.
+const SECRET_VARS: &[&str] = &[
+    "SYNC_JACKETT_URL",
+    "SYNC_JACKETT_PASSWORD",
+    "SYNC_JACKETT_PROXY",
+    "SYNC_JACKETT_HEADER",
+    "SYNC_JACKETT_BASIC_AUTH",
+    "SYNC_SONARR_URL",
+    "SYNC_SONARR_API_KEY",
+    "SYNC_SONARR_PROXY",
+    "SYNC_SONARR_HEADER",
+    "SYNC_SONARR_BASIC_AUTH",
+];
+
+/// The secret variable which a `*_FILE` variable gives the contents of, if it is one
+fn file_var(key: &str) -> Option<&'static str> {
+    let var = key.strip_suffix("_FILE")?;
+    SECRET_VARS.iter().copied().find(|&secret| secret == var)
+}
+
+/// Set each secret-bearing `SYNC_*` environment variable for which there is a `SYNC_*_FILE`
+/// variable to the contents of that file, as is usual for Docker and Kubernetes secrets
+pub fn load_env_files() -> Result<()> {
+    let files: Vec<_> = std::env::vars_os()
+        .filter_map(|(key, path)| Some((file_var(key.to_str()?)?, path)))
+        .collect();
+
+    for (var, path) in files {
+        if std::env::var_os(&var).is_some() {
+            return Err(Error::Config(format!(
+                "only one of {} and {}_FILE may be set",
+                var, var
+            )));
+        }
+        std::env::set_var(&var, read_file(Path::new(&path))?);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Credentials {
+        #[serde(default, deserialize_with = "optional")]
+        password: Option<String>,
+    }
+
+    #[test]
+    fn test_trim_line_ending() {
+        assert_eq!(trim_line_ending("abc123\n"), "abc123");
+        assert_eq!(trim_line_ending("abc123\r\n"), "abc123");
+        assert_eq!(trim_line_ending("abc123 "), "abc123 ");
+        assert_eq!(trim_line_ending("abc123\n\n"), "abc123\n");
+    }
+
+    #[test]
+    fn test_file_var() {
+        assert_eq!(
+            file_var("SYNC_SONARR_API_KEY_FILE"),
+            Some("SYNC_SONARR_API_KEY")
+        );
+        assert_eq!(
+            file_var("SYNC_JACKETT_PASSWORD_FILE"),
+            Some("SYNC_JACKETT_PASSWORD")
+        );
+        assert_eq!(file_var("SYNC_SONARR_API_KEY"), None);
+
+        // Options which already name a file aren't secrets to be read from one
+        assert_eq!(file_var("SYNC_CONFIG_FILE"), None);
+        assert_eq!(file_var("SYNC_SONARR_CONFIG_FILE"), None);
+    }
+
+    #[test]
+    fn test_deserialize_secrets() -> std::result::Result<(), toml::de::Error> {
+        let plain: Credentials = toml::from_str(r#"password = "hunter2""#)?;
+        assert_eq!(plain.password.as_deref(), Some("hunter2"));
+
+        let missing: Credentials = toml::from_str("")?;
+        assert_eq!(missing.password, None);
+
+        let file = toml::from_str::<Credentials>(r#"password = { file = "/nonexistent" }"#);
+        assert!(file.is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_secret_command() -> Result<()> {
+        let command = SecretCommand(r#"test "$1" = jackett/password && echo hunter2"#.to_owned());
+        assert_eq!(
+            command.fetch("jackett/password")?.as_deref(),
+            Some("hunter2")
+        );
+        assert!(command.fetch("sonarr/api-key").is_err());
+
+        let command = SecretCommand("true".to_owned());
+        assert_eq!(command.fetch("sonarr/api-key")?, None);
+        Ok(())
+    }
+}
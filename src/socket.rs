@@ -0,0 +1,429 @@
+use hyper::body::Bytes;
+use reqwest::cookie::{CookieStore, Jar};
+use reqwest::header::{self, HeaderMap};
+use reqwest::{Method, ResponseBuilderExt, StatusCode};
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use url::Url;
+
+use crate::{Error, Result};
+
+/// A service listening on a Unix socket, given as `unix:///path/to.sock`. The `host` and `path`
+/// query parameters give the host name to send and the service's base path, for proxies which
+/// serve several services on one socket ("unix:///run/proxy.sock?host=sonarr&path=/sonarr/").
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnixSocket {
+    pub path: PathBuf,
+    pub host: String,
+    pub base_path: String,
+}
+
+impl UnixSocket {
+    /// The socket a `unix://` URL refers to, or `None` for any other URL
+    pub fn from_url(url: &Url) -> Option<std::result::Result<Self, String>> {
+        if url.scheme() != "unix" {
+            return None;
+        }
+        Some(Self::parse(url))
+    }
+
+    fn parse(url: &Url) -> std::result::Result<Self, String> {
+        if url.path().is_empty() || url.path() == "/" {
+            return Err(format!("{} does not give a socket path", url));
+        }
+
+        let mut socket = Self {
+            path: PathBuf::from(url.path()),
+            host: "localhost".to_owned(),
+            base_path: "/".to_owned(),
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "host" => socket.host = value.into_owned(),
+                "path" => match value.trim_matches('/') {
+                    "" => socket.base_path = "/".to_owned(),
+                    path => socket.base_path = format!("/{}/", path),
+                },
+                other => return Err(format!("Unknown parameter {:?} in {}", other, url)),
+            }
+        }
+        Ok(socket)
+    }
+
+    /// The base URL of the service, for building requests which are then sent through the socket
+    pub fn base_url(&self) -> Result<Url> {
+        format!("http://{}{}", self.host, self.base_path)
+            .parse()
+            .map_err(|e| self.error(&e))
+    }
+
+    fn error(&self, e: &dyn std::fmt::Display) -> Error {
+        Error::Config(format!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// How many redirects are followed before the last one is handed back, as reqwest does
+const MAX_REDIRECTS: usize = 10;
+
+/// Sends requests through a Unix socket, keeping cookies and following redirects as our HTTP
+/// client does for services reached over the network
+#[derive(Clone)]
+pub struct SocketClient {
+    #[cfg(unix)]
+    client: hyper::Client<Connector>,
+    cookies: Option<Arc<Jar>>,
+}
+
+impl SocketClient {
+    #[cfg(unix)]
+    pub fn new(socket: &UnixSocket, cookies: bool) -> Result<Self> {
+        Ok(Self {
+            client: hyper::Client::builder().build(Connector(Arc::new(socket.path.clone()))),
+            cookies: if cookies { Some(Arc::default()) } else { None },
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(socket: &UnixSocket, _cookies: bool) -> Result<Self> {
+        Err(socket.error(&"Unix sockets are not supported on this platform"))
+    }
+
+    /// Send a request, within its timeout if it has one
+    pub async fn execute(
+        &self,
+        request: reqwest::Request,
+    ) -> std::result::Result<reqwest::Response, SocketError> {
+        match request.timeout().copied() {
+            Some(timeout) => tokio::time::timeout(timeout, self.follow_redirects(request))
+                .await
+                .map_err(|_| SocketError::Timeout)?,
+            None => self.follow_redirects(request).await,
+        }
+    }
+
+    async fn follow_redirects(
+        &self,
+        request: reqwest::Request,
+    ) -> std::result::Result<reqwest::Response, SocketError> {
+        let mut method = request.method().clone();
+        let mut url = request.url().clone();
+        let mut headers = request.headers().clone();
+        let mut body = request
+            .body()
+            .and_then(reqwest::Body::as_bytes)
+            .map(Bytes::copy_from_slice);
+
+        let mut redirects = 0;
+        loop {
+            let response = self
+                .send(method.clone(), &url, headers.clone(), body.clone())
+                .await?;
+            let status = response.status();
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| url.join(location).ok())
+                // Everything we send goes through the socket, so we can't follow a redirect away
+                .filter(|location| location.origin() == url.origin());
+
+            match location {
+                Some(location) if status.is_redirection() && redirects < MAX_REDIRECTS => {
+                    // As browsers do, anything but a 307 or 308 is followed with a GET
+                    if status != StatusCode::TEMPORARY_REDIRECT
+                        && status != StatusCode::PERMANENT_REDIRECT
+                        && method != Method::HEAD
+                    {
+                        method = Method::GET;
+                        body = None;
+                        headers.remove(header::CONTENT_TYPE);
+                        headers.remove(header::CONTENT_LENGTH);
+                    }
+                    url = location;
+                    redirects += 1;
+                }
+                _ => return into_response(response, url),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    async fn send(
+        &self,
+        method: Method,
+        url: &Url,
+        headers: HeaderMap,
+        body: Option<Bytes>,
+    ) -> std::result::Result<hyper::Response<Bytes>, SocketError> {
+        let mut request = hyper::Request::builder()
+            .method(method)
+            .uri(url.as_str())
+            .body(
+                body.map(hyper::Body::from)
+                    .unwrap_or_else(hyper::Body::empty),
+            )?;
+        *request.headers_mut() = headers;
+        if let Some(cookies) = self.cookies.as_ref().and_then(|jar| jar.cookies(url)) {
+            request.headers_mut().insert(header::COOKIE, cookies);
+        }
+
+        let response = self.client.request(request).await?;
+        if let Some(jar) = &self.cookies {
+            jar.set_cookies(
+                &mut response.headers().get_all(header::SET_COOKIE).iter(),
+                url,
+            );
+        }
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        Ok(hyper::Response::from_parts(parts, body))
+    }
+
+    #[cfg(not(unix))]
+    async fn send(
+        &self,
+        _method: Method,
+        _url: &Url,
+        _headers: HeaderMap,
+        _body: Option<Bytes>,
+    ) -> std::result::Result<hyper::Response<Bytes>, SocketError> {
+        unreachable!("socket clients can't be created on this platform")
+    }
+}
+
+impl fmt::Debug for SocketClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SocketClient")
+            .field("cookies", &self.cookies.is_some())
+            .finish()
+    }
+}
+
+/// The response as our HTTP client would give it, knowing which URL it came from
+fn into_response(
+    response: hyper::Response<Bytes>,
+    url: Url,
+) -> std::result::Result<reqwest::Response, SocketError> {
+    let (parts, body) = response.into_parts();
+    let mut builder = hyper::Response::builder()
+        .status(parts.status)
+        .version(parts.version)
+        .url(url);
+    if let Some(headers) = builder.headers_mut() {
+        *headers = parts.headers;
+    }
+    Ok(builder.body(body)?.into())
+}
+
+/// A request sent through a Unix socket which didn't get a response
+#[derive(Debug)]
+pub enum SocketError {
+    /// The request couldn't be sent, or its response couldn't be read
+    Http(hyper::Error),
+    /// The request couldn't be made from what we were given
+    Request(hyper::http::Error),
+    /// No response came before the request's timeout
+    Timeout,
+}
+
+impl SocketError {
+    /// Whether we couldn't connect to the socket at all, so the request certainly wasn't sent
+    pub fn is_connect(&self) -> bool {
+        matches!(self, Self::Http(e) if e.is_connect())
+    }
+}
+
+impl fmt::Display for SocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(e) => e.fmt(f),
+            Self::Request(e) => e.fmt(f),
+            Self::Timeout => f.write_str("operation timed out"),
+        }
+    }
+}
+
+impl std::error::Error for SocketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(e) => Some(e),
+            Self::Request(e) => Some(e),
+            Self::Timeout => None,
+        }
+    }
+}
+
+impl From<hyper::Error> for SocketError {
+    fn from(e: hyper::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<hyper::http::Error> for SocketError {
+    fn from(e: hyper::http::Error) -> Self {
+        Self::Request(e)
+    }
+}
+
+/// Connects to the socket, whatever the host in the request's URL
+#[cfg(unix)]
+#[derive(Clone)]
+struct Connector(Arc<PathBuf>);
+
+#[cfg(unix)]
+impl hyper::service::Service<hyper::Uri> for Connector {
+    type Response = Stream;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<Stream>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: hyper::Uri) -> Self::Future {
+        let path = self.0.clone();
+        Box::pin(async move { Ok(Stream(tokio::net::UnixStream::connect(&*path).await?)) })
+    }
+}
+
+/// A connection to the socket, as our HTTP client needs it
+#[cfg(unix)]
+struct Stream(tokio::net::UnixStream);
+
+#[cfg(unix)]
+impl hyper::client::connect::Connection for Stream {
+    fn connected(&self) -> hyper::client::connect::Connected {
+        hyper::client::connect::Connected::new()
+    }
+}
+
+#[cfg(unix)]
+impl AsyncRead for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+#[cfg(unix)]
+impl AsyncWrite for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket(url: &str) -> Option<std::result::Result<UnixSocket, String>> {
+        UnixSocket::from_url(&url.parse().unwrap())
+    }
+
+    #[test]
+    fn test_parse_socket_urls() {
+        assert_eq!(
+            socket("unix:///run/sonarr.sock"),
+            Some(Ok(UnixSocket {
+                path: "/run/sonarr.sock".into(),
+                host: "localhost".to_owned(),
+                base_path: "/".to_owned(),
+            }))
+        );
+        assert_eq!(
+            socket("unix:///run/proxy.sock?host=media&path=sonarr"),
+            Some(Ok(UnixSocket {
+                path: "/run/proxy.sock".into(),
+                host: "media".to_owned(),
+                base_path: "/sonarr/".to_owned(),
+            }))
+        );
+        assert_eq!(socket("http://sonarr:8989"), None);
+        assert!(matches!(socket("unix:///"), Some(Err(_))));
+        assert!(matches!(socket("unix:///run/a.sock?port=1"), Some(Err(_))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socket_client() -> Result<()> {
+        use hyper::service::service_fn;
+        use hyper::{Body, Request, Response};
+
+        let path = std::env::temp_dir().join(format!("indexer-sync-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(|request: Request<Body>| async move {
+                    let header = |name| {
+                        request
+                            .headers()
+                            .get(name)
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default()
+                            .to_owned()
+                    };
+                    match (request.method(), request.uri().path()) {
+                        (&Method::POST, "/sonarr/login") => Response::builder()
+                            .status(StatusCode::FOUND)
+                            .header(header::LOCATION, "/sonarr/home")
+                            .header(header::SET_COOKIE, "session=abc; Path=/")
+                            .body(Body::empty()),
+                        (&Method::GET, "/sonarr/home") => Response::builder().body(Body::from(
+                            format!("{} {}", header(header::HOST), header(header::COOKIE)),
+                        )),
+                        _ => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty()),
+                    }
+                });
+                tokio::spawn(hyper::server::conn::Http::new().serve_connection(stream, service));
+            }
+        });
+
+        let socket = UnixSocket {
+            path: path.clone(),
+            host: "media".to_owned(),
+            base_path: "/sonarr/".to_owned(),
+        };
+        let url = socket.base_url()?;
+        assert_eq!(url.as_str(), "http://media/sonarr/");
+
+        let client = SocketClient::new(&socket, true)?;
+        let request = reqwest::Client::new()
+            .post(url.join("login")?)
+            .body("password=hunter2")
+            .build()?;
+        let response = client.execute(request).await.unwrap();
+        assert_eq!(response.url().path(), "/sonarr/home");
+        assert_eq!(response.text().await?, "media session=abc");
+
+        let request = reqwest::Client::new().get(url.join("missing")?).build()?;
+        let response = client.execute(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        std::fs::remove_file(&path).unwrap();
+        Ok(())
+    }
+}
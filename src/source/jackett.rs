@@ -2,9 +2,11 @@ use serde::Deserialize;
 use serde_json::Value;
 use url::Url;
 
+use crate::http::{self, RetryPolicy, Upstream};
 use crate::FeedUrls;
 use crate::IndexerPrivacy;
 use crate::SourceIndexer;
+use crate::{Error, Result};
 use crate::{Potato, Rss, Torznab};
 
 #[derive(Debug, Deserialize)]
@@ -23,75 +25,167 @@ pub struct Indexer {
 }
 
 pub struct Jackett {
+    name: String,
+    /// The name of the Jackett instance, unless it is the default one
+    instance: Option<String>,
+    /// The URL we reach Jackett at
+    base: Url,
     url: Url,
-    feed_api_key: String,
-    client: reqwest::Client,
+    dashboard: Url,
+    /// Where destinations should find Jackett, rather than where Jackett says it is
+    feed_url: Option<Url>,
+    password: Option<String>,
+    client: http::Client,
+    retry: RetryPolicy,
 }
 
-// Jackett does not have an "official" API. The endpoints we need to hit to
-// get the list of indexers and the API key use session authentication. So,
-// we hit the homepage once to fill the cookie store with the requisite
-// cookie.
-async fn auth(url: Url) -> Result<(Url, reqwest::Client), Box<dyn std::error::Error>> {
-    let (url, pw) = crate::util::extract_single_auth_value(url);
-    let client = reqwest::Client::builder()
-        .cookie_store(true)
-        .gzip(true)
-        .build()?;
-
-    let dashboard = url.join("/UI/Dashboard")?;
-    let err = Err(
-        Box::new(crate::Error("Jackett password incorrect".to_owned()))
-            as Box<dyn std::error::Error>,
-    );
-
-    let initial_request = client.get(dashboard.clone()).send().await?;
-
-    if initial_request.url().path() != dashboard.path() {
-        if let Some(pw) = pw {
-            let login_request = client
-                .post(dashboard.clone())
-                .form(&[("password", pw)])
-                .send()
-                .await?;
-            if login_request.url().path() != dashboard.path() {
+/// Where Jackett's feeds are found, and the API key they need
+struct Feeds {
+    url: Url,
+    api_key: String,
+}
+
+/// Log in to the Jackett with the given name, using the given admin password or otherwise the one
+/// in the URL's userinfo, and a client set up with any TLS and proxy settings
+pub async fn new(
+    name: &str,
+    url: Url,
+    password: Option<String>,
+    retry: RetryPolicy,
+    client: http::ClientBuilder,
+) -> Result<Jackett> {
+    let (url, url_password) = crate::util::extract_single_auth_value(crate::util::base_url(url));
+    let password = password.or(url_password);
+    let client = client.cookie_store().gzip().build()?;
+
+    let jackett = Jackett {
+        name: name.to_owned(),
+        // The default instance keeps the identity indexers had before there could be several
+        instance: Some(name.to_owned()).filter(|name| name != "jackett"),
+        dashboard: url.join("UI/Dashboard")?,
+        url: url.join("api/v2.0/")?,
+        base: url,
+        feed_url: None,
+        password,
+        client,
+        retry,
+    };
+    jackett.auth().await?;
+
+    Ok(jackett)
+}
+
+impl Jackett {
+    /// Where destinations should find Jackett's feeds, when they can't reach it at the same URL as
+    /// we do. This replaces Jackett's base path override.
+    pub fn feed_url(mut self, url: Option<Url>) -> Self {
+        self.feed_url = url.map(crate::util::base_url);
+        self
+    }
+
+    /// Read where feeds are found and the API key they need from Jackett's configuration. This is
+    /// done for every sync, as either may have changed since we logged in.
+    async fn feeds(&self) -> Result<Feeds> {
+        let config: Value = self
+            .get(self.url.join("server/config")?)
+            .await?
+            .json()
+            .await?;
+
+        let api_key = config["api_key"]
+            .as_str()
+            .ok_or_else(|| Error::Schema("Unable to find API key in Jackett config".to_owned()))?
+            .to_owned();
+
+        let base_path_override = config["basepathoverride"]
+            .as_str()
+            .filter(|path| !path.trim_matches('/').is_empty());
+        let base = match (&self.feed_url, base_path_override) {
+            (Some(feed_url), _) => feed_url.clone(),
+            (None, Some(path)) => {
+                let base = feed_base(&self.base, path);
+                log::debug!("Jackett has a base path override; using feeds at {}", base);
+                base
+            }
+            (None, None) => self.base.clone(),
+        };
+
+        Ok(Feeds {
+            url: base.join("api/v2.0/")?,
+            api_key,
+        })
+    }
+
+    // Jackett does not have an "official" API. The endpoints we need to hit to
+    // get the list of indexers and the API key use session authentication. So,
+    // we hit the homepage once to fill the cookie store with the requisite
+    // cookie.
+    async fn auth(&self) -> Result<()> {
+        let err = Err(Error::Auth("Jackett password incorrect".to_owned()));
+
+        let initial_request = self
+            .retry
+            .send(self.upstream(), self.client.get(self.dashboard.clone()))
+            .await?;
+
+        if initial_request.url().path() != self.dashboard.path() {
+            if let Some(ref pw) = self.password {
+                // Logging in again is harmless, so this can be retried like anything else
+                let login_request = self
+                    .retry
+                    .send(
+                        self.upstream(),
+                        self.client
+                            .post(self.dashboard.clone())
+                            .form(&[("password", pw)]),
+                    )
+                    .await?;
+                if login_request.url().path() != self.dashboard.path() {
+                    return err;
+                }
+            } else {
                 return err;
             }
-        } else {
-            return err;
         }
-    }
 
-    Ok((url, client))
-}
+        Ok(())
+    }
 
-pub async fn new(url: Url) -> Result<Jackett, Box<dyn std::error::Error>> {
-    let (url, client) = auth(url).await?;
-    let url = url.join("/api/v2.0/")?;
+    fn upstream(&self) -> Upstream<'_> {
+        Upstream {
+            service: "jackett",
+            name: &self.name,
+            client: &self.client,
+        }
+    }
 
-    // Fetch Jackett configuration as JSON
-    let config: Value = client
-        .get(url.clone().join("server/config")?)
-        .send()
-        .await?
-        .json()
-        .await?;
+    /// GET an API endpoint, logging in again first if the session has expired
+    async fn get(&self, url: Url) -> Result<reqwest::Response> {
+        let get = || {
+            self.retry
+                .send(self.upstream(), self.client.get(url.clone()))
+        };
+        let response = get().await?;
+        if !is_expired(&response, &url) {
+            return Ok(response.error_for_status()?);
+        }
 
-    let api_key = config["api_key"].as_str().ok_or("Unable to find API key")?;
+        log::info!("Jackett session has expired; logging in again");
+        self.auth().await?;
 
-    Ok(Jackett {
-        url,
-        client,
-        feed_api_key: api_key.to_owned(),
-    })
-}
+        let response = get().await?;
+        if is_expired(&response, &url) {
+            return Err(Error::Auth(
+                "Jackett did not accept the new session".to_owned(),
+            ));
+        }
+        Ok(response.error_for_status()?)
+    }
 
-impl Jackett {
-    pub async fn fetch_indexers(&self) -> Result<Vec<crate::Indexer>, Box<dyn std::error::Error>> {
+    pub async fn fetch_indexers(&self) -> Result<Vec<crate::Indexer>> {
+        let feeds = self.feeds().await?;
         let jackett_indexers: Vec<Indexer> = self
-            .client
-            .get(self.url.clone().join("indexers?configured=true")?)
-            .send()
+            .get(self.url.join("indexers?configured=true")?)
             .await?
             .json()
             .await?;
@@ -99,41 +193,81 @@ impl Jackett {
         let indexers = jackett_indexers
             .into_iter()
             .map(|ind| {
-                let results_url = self
+                let results_url = feeds
                     .url
-                    .join(&format!("indexers/{}/results/torznab", &ind.id))
-                    .unwrap();
+                    .join(&format!("indexers/{}/results/torznab", &ind.id))?;
 
-                crate::Indexer {
+                Ok(crate::Indexer {
                     name: ind.name.clone(),
                     urls: FeedUrls {
                         newznab: None,
                         torznab: Some(Torznab {
-                            url: results_url.join("torznab").unwrap(),
-                            api_key: Some(self.feed_api_key.to_owned()),
+                            url: results_url.join("torznab")?,
+                            api_key: Some(feeds.api_key.to_owned()),
                             capabilities: ind.capabilities.clone(),
                         }),
                         potato: if ind.potato_enabled {
                             Some(Potato {
-                                url: results_url.join("potato").unwrap(),
-                                api_key: Some(self.feed_api_key.to_owned()),
+                                url: results_url.join("potato")?,
+                                api_key: Some(feeds.api_key.to_owned()),
                             })
                         } else {
                             None
                         },
                         rss: Some(Rss({
-                            let mut rss_url = self.url.join("rss").unwrap();
+                            let mut rss_url = feeds.url.join("rss")?;
                             rss_url
                                 .query_pairs_mut()
-                                .append_pair("api_key", &self.feed_api_key);
+                                .append_pair("api_key", &feeds.api_key);
                             rss_url
                         })),
                     },
                     privacy: ind.privacy,
-                    source: SourceIndexer::Jackett(ind),
-                }
+                    source: SourceIndexer::Jackett {
+                        instance: self.instance.clone(),
+                        indexer: ind,
+                    },
+                })
             })
-            .collect();
+            .collect::<Result<_>>()?;
         Ok(indexers)
     }
 }
+
+/// The base URL of a Jackett with a base path override. Jackett answers at its root as well as
+/// under the base path, but a reverse proxy in front of it might only pass the base path on, so
+/// feeds should always be under it.
+fn feed_base(url: &Url, base_path_override: &str) -> Url {
+    let base_path = format!("/{}/", base_path_override.trim_matches('/'));
+    if url.path().ends_with(&base_path) {
+        return url.clone();
+    }
+    let mut url = url.clone();
+    url.set_path(&base_path);
+    url
+}
+
+/// Without a valid session, Jackett either answers API requests with 401 or redirects them to the
+/// login page
+fn is_expired(response: &reqwest::Response, requested: &Url) -> bool {
+    response.status() == reqwest::StatusCode::UNAUTHORIZED
+        || response.url().path() != requested.path()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feed_base() {
+        let url = |s: &str| s.parse::<Url>().unwrap();
+        assert_eq!(
+            feed_base(&url("http://localhost:9117/"), "/jackett").as_str(),
+            "http://localhost:9117/jackett/"
+        );
+        assert_eq!(
+            feed_base(&url("https://proxy/jackett/"), "jackett/").as_str(),
+            "https://proxy/jackett/"
+        );
+    }
+}
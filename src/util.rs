@@ -1,13 +1,20 @@
+use serde::Deserialize;
+use std::str::FromStr;
 use url::Url;
 
-/// Checks if a passed string URL is a parseable URL with a https:// or http:// scheme
+/// Checks if a passed string URL is a parseable URL with a https:// or http:// scheme, or the
+/// unix:// URL of a socket serving HTTP
 pub fn is_http_url(url: &str) -> Result<(), String> {
     match Url::parse(url) {
         Err(e) => Err(e.to_string()),
         Ok(url) => match url.scheme() {
             "http" | "https" => Ok(()),
+            "unix" => match crate::socket::UnixSocket::from_url(&url) {
+                Some(socket) => socket.map(|_| ()),
+                None => Ok(()),
+            },
             scheme => Err(format!(
-                "URL must be an http:// or https:// URL (given {})",
+                "URL must be an http://, https:// or unix:// URL (given {})",
                 scheme
             )),
         },
@@ -27,3 +34,198 @@ pub fn extract_single_auth_value(url: Url) -> (Url, Option<String>) {
         }
     }
 }
+
+/// The URL with a trailing slash on its path, so that relative paths joined onto it keep its whole
+/// path ("http://host/sonarr" + "api" is "http://host/api", but "http://host/sonarr/" + "api" is
+/// "http://host/sonarr/api")
+pub fn base_url(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
+
+/// Whether a name given to a source or destination is made of letters, numbers, `-` and `_`
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// A URL given on the command line, optionally preceded by a name for it (`NAME=URL`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamedUrl {
+    pub name: Option<String>,
+    pub url: Url,
+}
+
+impl FromStr for NamedUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Anything before the URL's scheme is the name
+        let (name, url) = match s.find('=') {
+            Some(i) if !s[..i].contains(':') => (Some(s[..i].trim().to_owned()), &s[i + 1..]),
+            _ => (None, s),
+        };
+
+        if name.as_deref() == Some("") {
+            return Err(format!("Missing name before \"=\" in {:?}", s));
+        }
+
+        is_http_url(url)?;
+        Ok(Self {
+            name,
+            url: Url::parse(url).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+/// Replaces the start of URLs given to a destination, for when it reaches a source at a different
+/// address than we do (`FROM=TO`, or `{ from = FROM, to = TO }` in the config file)
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UrlRewrite {
+    pub from: Url,
+    pub to: Url,
+}
+
+impl UrlRewrite {
+    /// The rewritten URL, if it has the same scheme, host and port as `from` and its path is within
+    /// `from`'s path ("/jackett" covers "/jackett/api", but not "/jackettfoo/api")
+    pub fn apply(&self, url: &Url) -> Option<Url> {
+        if url.scheme() != self.from.scheme()
+            || url.host_str() != self.from.host_str()
+            || url.port_or_known_default() != self.from.port_or_known_default()
+        {
+            return None;
+        }
+        let from = self.from.path().trim_end_matches('/');
+        let rest = url.path().strip_prefix(from)?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+
+        let mut rewritten = self.to.clone();
+        let path = format!("{}{}", self.to.path().trim_end_matches('/'), rest);
+        rewritten.set_path(if path.is_empty() { "/" } else { &path });
+        rewritten.set_query(url.query());
+        rewritten.set_fragment(url.fragment());
+        Some(rewritten)
+    }
+}
+
+/// Rewrite a URL with the first rule which applies to it, if any
+pub fn rewrite_url(rewrites: &[UrlRewrite], url: &Url) -> Url {
+    rewrites
+        .iter()
+        .find_map(|rewrite| rewrite.apply(url))
+        .unwrap_or_else(|| url.clone())
+}
+
+impl FromStr for UrlRewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // FROM may have a query string (which has its own `=`), but TO's would never be used
+        let (from, to) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("Expected FROM=TO (given {:?})", s))?;
+        is_http_url(from)?;
+        is_http_url(to)?;
+        Ok(Self {
+            from: from.parse().map_err(|e: url::ParseError| e.to_string())?,
+            to: to.parse().map_err(|e: url::ParseError| e.to_string())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_named_url() {
+        let named: NamedUrl = "anime=http://key@sonarr:8989/".parse().unwrap();
+        assert_eq!(named.name.as_deref(), Some("anime"));
+        assert_eq!(named.url.as_str(), "http://key@sonarr:8989/");
+
+        let unnamed: NamedUrl = "http://sonarr:8989/?a=b".parse().unwrap();
+        assert_eq!(unnamed.name, None);
+        assert_eq!(unnamed.url.as_str(), "http://sonarr:8989/?a=b");
+
+        assert!("=http://sonarr:8989".parse::<NamedUrl>().is_err());
+        assert!("anime=ftp://sonarr".parse::<NamedUrl>().is_err());
+
+        let socket: NamedUrl = "anime=unix:///run/sonarr.sock".parse().unwrap();
+        assert_eq!(socket.url.scheme(), "unix");
+    }
+
+    #[test]
+    fn test_url_rewrites() {
+        let rewrites: Vec<UrlRewrite> = vec![
+            "http://localhost:9117=http://jackett:9117".parse().unwrap(),
+            "https://proxy/jackett/=http://jackett:9117/"
+                .parse()
+                .unwrap(),
+        ];
+        let rewrite = |url: &str| rewrite_url(&rewrites, &url.parse().unwrap()).to_string();
+
+        assert_eq!(
+            rewrite("http://localhost:9117/api/v2.0/indexers/rarbg/results/torznab"),
+            "http://jackett:9117/api/v2.0/indexers/rarbg/results/torznab"
+        );
+        assert_eq!(
+            rewrite("https://proxy/jackett/api/v2.0/rss?api_key=abc"),
+            "http://jackett:9117/api/v2.0/rss?api_key=abc"
+        );
+        assert_eq!(
+            rewrite("http://localhost:8080/api"),
+            "http://localhost:8080/api"
+        );
+        assert_eq!(
+            rewrite("https://proxy/jackettfoo/api/v2.0/rss"),
+            "https://proxy/jackettfoo/api/v2.0/rss"
+        );
+        assert_eq!(
+            rewrite("https://proxy:8443/jackett/api/v2.0/rss"),
+            "https://proxy:8443/jackett/api/v2.0/rss"
+        );
+        assert_eq!(rewrite("https://proxy/jackett"), "http://jackett:9117/");
+
+        let rewrite: UrlRewrite = "http://localhost:9117/?a=b=http://jackett:9117"
+            .parse()
+            .unwrap();
+        assert_eq!(rewrite.from.as_str(), "http://localhost:9117/?a=b");
+        assert_eq!(rewrite.to.as_str(), "http://jackett:9117/");
+
+        assert!("http://localhost:9117".parse::<UrlRewrite>().is_err());
+        assert!("http://localhost:9117=jackett"
+            .parse::<UrlRewrite>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_base_url() {
+        let base = |url: &str| base_url(url.parse().unwrap()).to_string();
+        assert_eq!(base("http://host"), "http://host/");
+        assert_eq!(base("http://host/sonarr"), "http://host/sonarr/");
+        assert_eq!(base("http://host/sonarr/?a=b"), "http://host/sonarr/?a=b");
+
+        let url = base_url("https://host/jackett".parse().unwrap());
+        assert_eq!(
+            url.join("api/v2.0/").unwrap().as_str(),
+            "https://host/jackett/api/v2.0/"
+        );
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("vpn"));
+        assert!(is_valid_name("jackett_2-b"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("my vpn"));
+        assert!(!is_valid_name("vpn}"));
+    }
+}